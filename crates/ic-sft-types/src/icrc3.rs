@@ -13,12 +13,20 @@ use std::{borrow::Cow, convert::From, ops::Deref, string::ToString};
 use crate::{nat_to_u64, Metadata, Value};
 
 pub use icrc_ledger_types::icrc3::{
-    archive::{GetArchivesArgs, GetArchivesResult},
+    archive::{GetArchivesArgs, GetArchivesResult, ICRC3ArchiveInfo, QueryArchiveFn},
     blocks::{
-        BlockWithId, GetBlocksRequest, GetBlocksResult, ICRC3DataCertificate, SupportedBlockType,
+        ArchivedBlocks, BlockWithId, GetBlocksRequest, GetBlocksResult, ICRC3DataCertificate,
+        SupportedBlockType,
     },
 };
 
+// The init argument of the archive canister spawned by the SFT canister.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ArchiveInitArg {
+    pub ledger_id: Principal,
+    pub start: u64, // the index of the first block in the archive
+}
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Block(ICRC3GenericBlock);
 
@@ -184,11 +192,17 @@ impl TryFrom<Block> for Transaction {
 }
 
 impl Transaction {
+    // Sets the `created_at_time` provided by the caller, it is recorded as "ts" in the "tx" field of the block.
+    pub fn with_created_at_time(mut self, created_at_time: Option<u64>) -> Self {
+        self.created_at_time = created_at_time;
+        self
+    }
+
     pub fn mint(
         now_ns: u64,
         tid: u64,
         from: Option<Principal>,
-        to: Principal,
+        to: Account,
         meta: Metadata,
         memo: Option<Memo>,
     ) -> Self {
@@ -200,10 +214,7 @@ impl Transaction {
                 owner,
                 subaccount: None,
             }),
-            to: Some(Account {
-                owner: to,
-                subaccount: None,
-            }),
+            to: Some(to),
             meta: Some(meta),
             memo,
             ..Default::default()
@@ -213,46 +224,28 @@ impl Transaction {
     pub fn burn(
         now_ns: u64,
         tid: u64,
-        from: Principal,
-        to: Option<Principal>,
+        from: Account,
+        to: Option<Account>,
         memo: Option<Memo>,
     ) -> Self {
         Transaction {
             ts: now_ns,
             op: "7burn".to_string(),
             tid,
-            from: Some(Account {
-                owner: from,
-                subaccount: None,
-            }),
-            to: to.map(|owner| Account {
-                owner,
-                subaccount: None,
-            }),
+            from: Some(from),
+            to,
             memo,
             ..Default::default()
         }
     }
 
-    pub fn transfer(
-        now_ns: u64,
-        tid: u64,
-        from: Principal,
-        to: Principal,
-        memo: Option<Memo>,
-    ) -> Self {
+    pub fn transfer(now_ns: u64, tid: u64, from: Account, to: Account, memo: Option<Memo>) -> Self {
         Transaction {
             ts: now_ns,
             op: "7xfer".to_string(),
             tid,
-            from: Some(Account {
-                owner: from,
-                subaccount: None,
-            }),
-            to: Some(Account {
-                owner: to,
-                subaccount: None,
-            }),
+            from: Some(from),
+            to: Some(to),
             memo,
             ..Default::default()
         }
@@ -282,8 +275,8 @@ impl Transaction {
     pub fn approve(
         now_ns: u64,
         tid: u64,
-        from: Principal,
-        spender: Principal,
+        from: Account,
+        spender: Account,
         exp_sec: Option<u64>,
         memo: Option<Memo>,
     ) -> Self {
@@ -291,14 +284,8 @@ impl Transaction {
             ts: now_ns,
             op: "37approve".to_string(),
             tid,
-            from: Some(Account {
-                owner: from,
-                subaccount: None,
-            }),
-            spender: Some(Account {
-                owner: spender,
-                subaccount: None,
-            }),
+            from: Some(from),
+            spender: Some(spender),
             exp: exp_sec,
             memo,
             ..Default::default()
@@ -307,22 +294,16 @@ impl Transaction {
 
     pub fn approve_collection(
         now_ns: u64,
-        from: Principal,
-        spender: Principal,
+        from: Account,
+        spender: Account,
         exp_sec: Option<u64>,
         memo: Option<Memo>,
     ) -> Self {
         Transaction {
             ts: now_ns,
             op: "37approve_coll".to_string(),
-            from: Some(Account {
-                owner: from,
-                subaccount: None,
-            }),
-            spender: Some(Account {
-                owner: spender,
-                subaccount: None,
-            }),
+            from: Some(from),
+            spender: Some(spender),
             exp: exp_sec,
             memo,
             ..Default::default()
@@ -332,22 +313,16 @@ impl Transaction {
     pub fn revoke(
         now_ns: u64,
         tid: u64,
-        from: Principal,
-        spender: Option<Principal>,
+        from: Account,
+        spender: Option<Account>,
         memo: Option<Memo>,
     ) -> Self {
         Transaction {
             ts: now_ns,
             op: "37revoke".to_string(),
             tid,
-            from: Some(Account {
-                owner: from,
-                subaccount: None,
-            }),
-            spender: spender.map(|owner| Account {
-                owner,
-                subaccount: None,
-            }),
+            from: Some(from),
+            spender,
             memo,
             ..Default::default()
         }
@@ -355,21 +330,15 @@ impl Transaction {
 
     pub fn revoke_collection(
         now_ns: u64,
-        from: Principal,
-        spender: Option<Principal>,
+        from: Account,
+        spender: Option<Account>,
         memo: Option<Memo>,
     ) -> Self {
         Transaction {
             ts: now_ns,
             op: "37revoke_coll".to_string(),
-            from: Some(Account {
-                owner: from,
-                subaccount: None,
-            }),
-            spender: spender.map(|owner| Account {
-                owner,
-                subaccount: None,
-            }),
+            from: Some(from),
+            spender,
             memo,
             ..Default::default()
         }
@@ -378,27 +347,18 @@ impl Transaction {
     pub fn transfer_from(
         now_ns: u64,
         tid: u64,
-        from: Principal,
-        to: Principal,
-        spender: Principal,
+        from: Account,
+        to: Account,
+        spender: Account,
         memo: Option<Memo>,
     ) -> Self {
         Transaction {
             ts: now_ns,
             op: "37xfer".to_string(),
             tid,
-            from: Some(Account {
-                owner: from,
-                subaccount: None,
-            }),
-            to: Some(Account {
-                owner: to,
-                subaccount: None,
-            }),
-            spender: Some(Account {
-                owner: spender,
-                subaccount: None,
-            }),
+            from: Some(from),
+            to: Some(to),
+            spender: Some(spender),
             memo,
             ..Default::default()
         }
@@ -28,6 +28,7 @@ pub enum ApproveTokenError {
     NonExistingTokenId,
     TooOld,
     CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
     GenericError { error_code: Nat, message: String },
     GenericBatchError { error_code: Nat, message: String },
 }
@@ -44,6 +45,7 @@ pub enum ApproveCollectionError {
     InvalidSpender,
     TooOld,
     CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
     GenericError { error_code: Nat, message: String },
     GenericBatchError { error_code: Nat, message: String },
 }
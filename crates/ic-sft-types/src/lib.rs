@@ -1,9 +1,10 @@
 use candid::{CandidType, Nat, Principal};
 use icrc_ledger_types::icrc::generic_value::{ICRC3Map, ICRC3Value};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 use num_traits::cast::ToPrimitive;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
-use std::{collections::BTreeSet, convert::From, string::ToString};
+use std::{convert::From, string::ToString};
 
 pub mod icrc3;
 pub mod icrc37;
@@ -76,6 +77,10 @@ pub struct InitArg {
     pub permitted_drift: Option<u64>,
     pub max_approvals_per_token_or_collection: Option<u16>,
     pub max_revoke_approvals: Option<u16>,
+    pub managers_can_burn: Option<bool>, // lets the Burner role burn the tokens held by anyone
+    pub archive_trigger_threshold: Option<u64>,
+    pub max_blocks_per_archive: Option<u64>,
+    pub archive_cycles: Option<u64>,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -95,6 +100,28 @@ pub struct UpdateCollectionArg {
     pub permitted_drift: Option<u64>,
     pub max_approvals_per_token_or_collection: Option<u16>,
     pub max_revoke_approvals: Option<u16>,
+    pub managers_can_burn: Option<bool>, // lets the Burner role burn the tokens held by anyone
+}
+
+// The archiving settings, null leaves the setting unchanged.
+#[derive(CandidType, Deserialize)]
+pub struct SetArchiveSettingsArg {
+    pub archive_trigger_threshold: Option<u64>, // 0 disables archiving
+    pub max_blocks_per_archive: Option<u64>,
+    pub archive_cycles: Option<u64>,
+}
+
+// The permissions that can be granted to principals by the controllers.
+#[derive(
+    CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum Role {
+    CollectionEditor, // updates the collection
+    TokenCreator,     // creates tokens and updates the tokens of any author
+    Minter,           // mints any token without quota, use the per-token grants to limit a minter
+    Burner,           // burns tokens held by anyone if `managers_can_burn` is enabled
+    AssetUploader,    // uploads assets and their encoded variants
+    Pauser,           // pauses and unpauses the collection operations
 }
 
 #[derive(CandidType, Deserialize, Serialize)]
@@ -110,6 +137,8 @@ pub struct CreateTokenArg {
     pub asset_name: String,
     pub asset_content_type: String,
     pub asset_content: ByteBuf,
+    pub asset_hash: Option<[u8; 32]>, // a committed upload, `asset_content` should be empty
+    pub assets: Option<Vec<AssetSlotArg>>, // additional named assets
     pub metadata: Metadata,
     pub supply_cap: Option<u32>,
     pub author: Principal,
@@ -124,26 +153,156 @@ pub struct UpdateTokenArg {
     pub asset_name: Option<String>,
     pub asset_content_type: Option<String>,
     pub asset_content: Option<ByteBuf>,
+    pub asset_hash: Option<[u8; 32]>,      // a committed upload
+    pub assets: Option<Vec<AssetSlotArg>>, // adds or replaces the named assets
     pub metadata: Option<Metadata>,
     pub supply_cap: Option<u32>,
     pub author: Option<Principal>,
 }
 
+#[derive(CandidType, Deserialize)]
+pub struct AssetSlotArg {
+    pub slot: String, // for example, "thumbnail", "image" or "animation"
+    pub asset_name: String,
+    pub asset_content_type: String,
+    pub asset_content: ByteBuf,
+    pub asset_hash: Option<[u8; 32]>, // a committed upload, `asset_content` should be empty
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CreateUploadArg {
+    pub asset_hash: [u8; 32], // SHA3-256 of the whole asset content
+    pub asset_size: u64,
+    pub asset_content_type: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UploadChunkArg {
+    pub upload_id: u32,
+    pub index: u32, // chunks are assembled in order of index
+    pub content: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetAssetEncodingArg {
+    pub asset_hash: [u8; 32],           // SHA3-256 of the identity content
+    pub content_encoding: String,       // "gzip" or "br"
+    pub encoded_hash: Option<[u8; 32]>, // the committed upload of the encoded content, null removes the variant
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct ListTokensFilter {
+    pub author: Option<Principal>,
+    pub minted: Option<bool>, // true for minted tokens, false for unminted drafts
+    pub created_after: Option<u64>, // in seconds, inclusive
+    pub created_before: Option<u64>, // in seconds, exclusive
+}
+
+// A page of the scanned Tokens, the scan stops at `take` matched Tokens or a maximum number of scanned Tokens.
+#[derive(CandidType, Deserialize)]
+pub struct TokensPage {
+    pub tokens: Vec<TokenInfo>,
+    pub last_scanned_id: Option<Nat>, // the `prev` to continue the scan, null if the scan is completed
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SearchTokensQuery {
+    pub name_prefix: Option<String>,      // case-insensitive
+    pub attributes: Vec<(String, Value)>, // Text or Nat metadata attributes, all should match
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TokenInfo {
+    pub id: Nat,
+    pub name: String,
+    pub description: Option<String>,
+    pub asset_name: String,
+    pub asset_content_type: String,
+    pub asset_hash: [u8; 32],
+    pub author: Principal,
+    pub supply_cap: Option<u32>,
+    pub total_supply: u32, // units in circulation
+    pub minted: u32,       // units ever minted, including the burned ones
+    pub created_at: u64,   // in seconds
+    pub updated_at: u64,   // in seconds
+}
+
+// The operations to pause or unpause, null leaves the operation unchanged.
+#[derive(CandidType, Deserialize)]
+pub struct SetPausedArg {
+    pub transfers: Option<bool>,
+    pub transfer_from: Option<bool>,
+    pub mint: Option<bool>,
+    pub approvals: Option<bool>,
+    pub token_creation: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetTokenMinterArg {
+    pub token_id: Nat,
+    pub minter: Principal,
+    pub quota: Option<u32>, // the remaining units the minter can mint, null means unlimited
+    pub expires_at: Option<u64>, // as nanoseconds since the UNIX epoch in the UTC timezone
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TokenMinter {
+    pub minter: Principal,
+    pub quota: Option<u32>,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AuditLogEntry {
+    pub index: Nat,
+    pub actor: Principal,
+    pub operation: String,
+    pub before: Metadata, // the changed fields before the operation
+    pub after: Metadata,  // the changed fields after the operation
+    pub timestamp: u64,   // as nanoseconds since the UNIX epoch in the UTC timezone
+}
+
 #[derive(CandidType, Deserialize, Clone)]
 pub struct MintArg {
     pub token_id: Nat,
-    pub holders: BTreeSet<Principal>,
+    pub holders: Vec<Account>, // one unit is minted to each holder
+    pub memo: Option<Memo>,
+    pub created_at_time: Option<u64>,
 }
 
 #[derive(CandidType, Serialize, Clone)]
 pub enum MintError {
     NonExistingTokenId,
     SupplyCapReached,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
     GenericBatchError { error_code: Nat, message: String },
 }
 
 pub type MintResult = Result<Nat, MintError>;
 
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BurnArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub token_id: Nat,
+    pub memo: Option<Memo>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum BurnError {
+    NonExistingTokenId,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type BurnResult = Result<Nat, BurnError>;
+
 #[derive(CandidType, Serialize, Clone, Debug, PartialEq, Eq)]
 pub struct SupportedStandard {
     pub name: String,
@@ -0,0 +1,150 @@
+use candid::{Nat, Principal};
+use ciborium::{from_reader, into_writer};
+use ic_sft_types::{ArchiveInitArg, Block, BlockWithId, GetBlocksRequest, GetBlocksResult, Value};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    storable::Bound,
+    DefaultMemoryImpl, StableCell, StableLog, Storable,
+};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, cell::RefCell};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
+const BLOCKS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(1);
+const BLOCKS_DATA_MEMORY_ID: MemoryId = MemoryId::new(2);
+
+const MAX_BLOCKS_PER_RESPONSE: u64 = 100;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct State {
+    ledger_id: Principal,
+    start: u64, // the index of the first block in the archive
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            ledger_id: Principal::anonymous(),
+            start: 0,
+        }
+    }
+}
+
+impl Storable for State {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode State data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode State data")
+    }
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static STATE: RefCell<StableCell<State, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(STATE_MEMORY_ID)),
+            State::default()
+        ).expect("failed to init STATE store")
+    );
+
+    static BLOCKS: RefCell<StableLog<Block, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(BLOCKS_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(BLOCKS_DATA_MEMORY_ID)),
+        ).expect("failed to init BLOCKS store")
+    );
+}
+
+fn is_ledger() -> Result<(), String> {
+    if STATE.with(|r| r.borrow().get().ledger_id) == ic_cdk::caller() {
+        Ok(())
+    } else {
+        Err("caller is not the ledger".to_string())
+    }
+}
+
+#[ic_cdk::init]
+fn init(args: ArchiveInitArg) {
+    STATE.with(|r| {
+        r.borrow_mut()
+            .set(State {
+                ledger_id: args.ledger_id,
+                start: args.start,
+            })
+            .expect("failed to set STATE data");
+    });
+}
+
+// Appends the blocks starting at the `start` index, returns the index of the next block.
+// Blocks that have been appended already are skipped, so the ledger can retry safely.
+#[ic_cdk::update(guard = "is_ledger")]
+fn append_blocks(start: u64, blocks: Vec<Value>) -> Result<u64, String> {
+    let first = STATE.with(|r| r.borrow().get().start);
+    BLOCKS.with(|r| {
+        let logs = r.borrow_mut();
+        let next = first + logs.len();
+        if start > next {
+            return Err(format!("expected block index {}, got {}", next, start));
+        }
+
+        let skip = (next - start) as usize;
+        for blk in blocks.into_iter().skip(skip) {
+            let blk = Block::try_from(blk)?;
+            logs.append(&blk)
+                .map_err(|err| format!("failed to append block, error {:?}", err))?;
+        }
+        Ok(first + logs.len())
+    })
+}
+
+#[ic_cdk::query]
+fn icrc3_get_blocks(args: Vec<GetBlocksRequest>) -> GetBlocksResult {
+    let first = STATE.with(|r| r.borrow().get().start);
+    BLOCKS.with(|r| {
+        let logs = r.borrow();
+        let mut blocks = vec![];
+        for arg in args {
+            let (start, length) = arg
+                .as_start_and_length()
+                .unwrap_or_else(|msg| ic_cdk::api::trap(&msg));
+            // the blocks before the first block of the archive are skipped
+            let skip = first.saturating_sub(start);
+            let length = length.saturating_sub(skip);
+            let start = start.max(first);
+            let offset = start - first;
+            let max_length = MAX_BLOCKS_PER_RESPONSE.saturating_sub(blocks.len() as u64);
+            let length = max_length
+                .min(length)
+                .min(logs.len().saturating_sub(offset));
+            for i in 0..length {
+                match logs.get(offset + i) {
+                    None => break,
+                    Some(block) => {
+                        blocks.push(BlockWithId {
+                            id: Nat::from(start + i),
+                            block: block.into_inner(),
+                        });
+                    }
+                }
+            }
+        }
+
+        GetBlocksResult {
+            log_length: Nat::from(first + logs.len()),
+            blocks,
+            archived_blocks: vec![],
+        }
+    })
+}
+
+ic_cdk::export_candid!();
@@ -1,6 +1,7 @@
 use crate::{store, utils::to_cbor_bytes};
+use candid::Nat;
 use icrc_ledger_types::icrc3::{
-    archive::{GetArchivesArgs, GetArchivesResult},
+    archive::{GetArchivesArgs, GetArchivesResult, ICRC3ArchiveInfo},
     blocks::{GetBlocksRequest, GetBlocksResult, ICRC3DataCertificate, SupportedBlockType},
 };
 use serde_bytes::ByteBuf;
@@ -62,8 +63,28 @@ pub fn icrc3_get_tip_certificate() -> Option<ICRC3DataCertificate> {
 }
 
 #[ic_cdk::query]
-pub fn icrc3_get_archives(_args: GetArchivesArgs) -> GetArchivesResult {
-    vec![] // TODO: implement
+pub fn icrc3_get_archives(args: GetArchivesArgs) -> GetArchivesResult {
+    store::collection::with(|c| {
+        let skip = match args.from {
+            Some(from) => c
+                .archives
+                .iter()
+                .position(|a| a.canister_id == from)
+                .map_or(0, |i| i + 1),
+            None => 0,
+        };
+        // the archives without blocks, such as a pending one, are not listed
+        c.archives
+            .iter()
+            .skip(skip)
+            .filter(|a| a.end > a.start)
+            .map(|a| ICRC3ArchiveInfo {
+                canister_id: a.canister_id,
+                start: Nat::from(a.start),
+                end: Nat::from(a.end - 1),
+            })
+            .collect()
+    })
 }
 
 #[ic_cdk::query]
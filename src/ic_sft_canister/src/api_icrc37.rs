@@ -1,5 +1,11 @@
-use crate::{is_authenticated, schema::Validate, store, ANONYMOUS, SECOND};
-use candid::{Nat, Principal};
+use crate::{
+    is_authenticated,
+    schema::Validate,
+    store::{self, AccountKey},
+    utils::tx_hash,
+    ANONYMOUS, SECOND,
+};
+use candid::Nat;
 use ic_sft_types::{
     nat_to_u64, ApproveCollectionArg, ApproveCollectionError, ApproveCollectionResult,
     ApproveTokenArg, ApproveTokenError, ApproveTokenResult, CollectionApproval, IsApprovedArg,
@@ -45,22 +51,15 @@ pub fn icrc37_is_approved(args: Vec<IsApprovedArg>) -> Vec<bool> {
     }
 
     let now_sec = ic_cdk::api::time() / SECOND;
-    let spenders: Vec<&Principal> = args.iter().map(|a| &a.spender.owner).collect();
-    let mut res = store::approvals::spenders_is_approved(&caller, &spenders, now_sec);
-    let mut query_idx: Vec<usize> = Vec::new();
-    let mut query: Vec<(SftId, &Principal)> = Vec::new();
-    for (i, a) in args.iter().enumerate() {
-        if !res[i] {
-            query_idx.push(i);
-            query.push((SftId::from(&a.token_id), &a.spender.owner));
-        }
-    }
-    let res2 = store::holder_tokens::spenders_is_approved(&caller, &query, now_sec);
-    for (i, idx) in query_idx.into_iter().enumerate() {
-        res[idx] = res2[i];
-    }
-
-    res
+    args.iter()
+        .map(|arg| {
+            let from = AccountKey::new(caller, arg.from_subaccount);
+            let spender = AccountKey::from(&arg.spender);
+            let id = SftId::from(&arg.token_id);
+            store::approvals::is_approved(&from, &spender, now_sec)
+                || store::holder_tokens::is_approved(&from, &spender, id.0, id.1, now_sec)
+        })
+        .collect()
 }
 
 // Returns the token-level approvals that exist for the given `token_id`.
@@ -81,7 +80,7 @@ pub fn icrc37_get_token_approvals(
     store::holder_tokens::with(|r| {
         if let Some(tokens) = r.get(&holder) {
             if let Some(approvals) = tokens.get_approvals(id.0, id.1) {
-                let prev = prev.map(|p| p.approval_info.spender.owner);
+                let prev = prev.map(|p| AccountKey::from(&p.approval_info.spender));
                 let mut res: Vec<TokenApproval> = Vec::with_capacity(take as usize);
                 for approval in approvals.iter() {
                     if let Some(ref prev) = prev {
@@ -91,7 +90,7 @@ pub fn icrc37_get_token_approvals(
                     }
                     res.push(TokenApproval {
                         token_id: token_id.clone(),
-                        approval_info: store::Approvals::to_info(approval),
+                        approval_info: store::Approvals::to_info(&holder, approval),
                     });
 
                     if res.len() as u16 >= take {
@@ -115,9 +114,10 @@ pub fn icrc37_get_collection_approvals(
 ) -> Vec<CollectionApproval> {
     let take = store::collection::take_value(take.as_ref().map(nat_to_u64));
 
+    let owner = AccountKey::from(&owner);
     store::approvals::with(|r| {
-        if let Some(approvals) = r.get(&owner.owner) {
-            let prev = prev.map(|p| p.spender.owner);
+        if let Some(approvals) = r.get(&owner) {
+            let prev = prev.map(|p| AccountKey::from(&p.spender));
             let mut res: Vec<CollectionApproval> = Vec::with_capacity(take as usize);
             for approval in approvals.iter() {
                 if let Some(ref prev) = prev {
@@ -125,7 +125,7 @@ pub fn icrc37_get_collection_approvals(
                         continue;
                     }
                 }
-                res.push(store::Approvals::to_info(approval));
+                res.push(store::Approvals::to_info(&owner, approval));
 
                 if res.len() as u16 >= take {
                     return res;
@@ -152,63 +152,91 @@ pub fn icrc37_approve_tokens(args: Vec<ApproveTokenArg>) -> Vec<Option<ApproveTo
         ic_cdk::trap("exceeds max update batch size");
     }
 
+    if store::collection::with(|c| c.paused.approvals) {
+        return vec![
+            Some(Err(ApproveTokenError::GenericError {
+                error_code: Nat::from(0u64),
+                message: "approvals are paused".to_string(),
+            }));
+            args.len()
+        ];
+    }
+
     store::holder_tokens::with_mut(|r| {
         let mut res: Vec<Option<ApproveTokenResult>> = vec![None; args.len()];
         let now = ic_cdk::api::time();
-        match r.get(&caller) {
-            None => {
-                res.fill(Some(Err(ApproveTokenError::Unauthorized)));
+        for (index, arg) in args.iter().enumerate() {
+            if let Err(err) = arg.validate(now, &caller, &settings) {
+                res[index] = Some(Err(err));
+                continue;
             }
-            Some(mut tokens) => {
-                for (index, arg) in args.iter().enumerate() {
-                    if let Err(err) = arg.validate(now, &caller, &settings) {
-                        res[index] = Some(Err(err));
-                        continue;
-                    }
 
-                    let id = SftId::from(&arg.token_id);
-                    match tokens.insert_approvals(
-                        settings.max_approvals_per_token_or_collection,
-                        id.0,
-                        id.1,
-                        arg.approval_info.spender.owner,
-                        arg.approval_info.created_at_time.unwrap_or_default() / SECOND,
-                        arg.approval_info.expires_at.unwrap_or_default() / SECOND,
-                    ) {
-                        Ok(_) => {
-                            let tx_log = Transaction::approve(
-                                now,
-                                id.to_u64(),
-                                caller,
-                                arg.approval_info.spender.owner,
-                                arg.approval_info.expires_at,
-                                arg.approval_info.memo.to_owned(),
-                            );
-
-                            match store::blocks::append(tx_log) {
-                                Ok(idx) => {
-                                    res[index] = Some(Ok(Nat::from(idx)));
-                                }
-                                Err(err) => {
-                                    res[index] = Some(Err(ApproveTokenError::GenericBatchError {
-                                        error_code: Nat::from(0u64),
-                                        message: err,
-                                    }));
-                                    r.insert(caller, tokens);
-                                    // break up when append log failed.
-                                    return res;
-                                }
+            let dedup = arg
+                .approval_info
+                .created_at_time
+                .map(|ts| (tx_hash("37approve", &caller, arg), ts));
+            if let Some(idx) = dedup.and_then(|(h, _)| store::tx_dedup::find(&h, now, &settings)) {
+                res[index] = Some(Err(ApproveTokenError::Duplicate {
+                    duplicate_of: Nat::from(idx),
+                }));
+                continue;
+            }
+
+            let from = AccountKey::new(caller, arg.approval_info.from_subaccount);
+            let mut tokens = match r.get(&from) {
+                Some(tokens) => tokens,
+                None => {
+                    res[index] = Some(Err(ApproveTokenError::Unauthorized));
+                    continue;
+                }
+            };
+
+            let id = SftId::from(&arg.token_id);
+            let spender = AccountKey::from(&arg.approval_info.spender);
+            match tokens.insert_approvals(
+                settings.max_approvals_per_token_or_collection,
+                id.0,
+                id.1,
+                spender,
+                arg.approval_info.created_at_time.unwrap_or_default() / SECOND,
+                arg.approval_info.expires_at.unwrap_or_default() / SECOND,
+                now / SECOND,
+            ) {
+                Ok(_) => {
+                    let tx_log = Transaction::approve(
+                        now,
+                        id.to_u64(),
+                        Account::from(from),
+                        Account::from(spender),
+                        arg.approval_info.expires_at,
+                        arg.approval_info.memo.to_owned(),
+                    )
+                    .with_created_at_time(arg.approval_info.created_at_time);
+
+                    match store::blocks::append(tx_log) {
+                        Ok(idx) => {
+                            res[index] = Some(Ok(Nat::from(idx)));
+                            if let Some((hash, ts)) = dedup {
+                                store::tx_dedup::insert(hash, ts, idx);
                             }
+                            r.insert(from, tokens);
                         }
                         Err(err) => {
-                            res[index] = Some(Err(err));
+                            res[index] = Some(Err(ApproveTokenError::GenericBatchError {
+                                error_code: Nat::from(0u64),
+                                message: err,
+                            }));
+                            // break up when append log failed.
+                            return res;
                         }
                     }
                 }
-
-                r.insert(caller, tokens);
+                Err(err) => {
+                    res[index] = Some(Err(err));
+                }
             }
         }
+
         res
     })
 }
@@ -229,62 +257,83 @@ pub fn icrc37_approve_collection(
         ic_cdk::trap("exceeds max update batch size");
     }
 
+    if store::collection::with(|c| c.paused.approvals) {
+        return vec![
+            Some(Err(ApproveCollectionError::GenericError {
+                error_code: Nat::from(0u64),
+                message: "approvals are paused".to_string(),
+            }));
+            args.len()
+        ];
+    }
+
     store::approvals::with_mut(|r| {
         let mut res: Vec<Option<ApproveCollectionResult>> = vec![None; args.len()];
         let now = ic_cdk::api::time();
-        let mut approvals = r.get(&caller).unwrap_or_default();
-        let mut total = approvals.total();
-        if total >= settings.max_approvals_per_token_or_collection as u32 {
-            res.fill(Some(Err(ApproveCollectionError::GenericBatchError {
-                error_code: Nat::from(0u64),
-                message: "exceeds the maximum number of approvals".to_string(),
-            })));
-        } else {
-            for (index, arg) in args.iter().enumerate() {
-                if let Err(err) = arg.validate(now, &caller, &settings) {
-                    res[index] = Some(Err(err));
-                    continue;
+        for (index, arg) in args.iter().enumerate() {
+            if let Err(err) = arg.validate(now, &caller, &settings) {
+                res[index] = Some(Err(err));
+                continue;
+            }
+
+            let dedup = arg
+                .approval_info
+                .created_at_time
+                .map(|ts| (tx_hash("37approve_coll", &caller, arg), ts));
+            if let Some(idx) = dedup.and_then(|(h, _)| store::tx_dedup::find(&h, now, &settings)) {
+                res[index] = Some(Err(ApproveCollectionError::Duplicate {
+                    duplicate_of: Nat::from(idx),
+                }));
+                continue;
+            }
+
+            let from = AccountKey::new(caller, arg.approval_info.from_subaccount);
+            let spender = AccountKey::from(&arg.approval_info.spender);
+            let mut approvals = r.get(&from).unwrap_or_default();
+            if !approvals.can_approve(
+                &spender,
+                settings.max_approvals_per_token_or_collection,
+                now / SECOND,
+            ) {
+                res[index] = Some(Err(ApproveCollectionError::GenericBatchError {
+                    error_code: Nat::from(0u64),
+                    message: "exceeds the maximum number of approvals".to_string(),
+                }));
+                continue;
+            }
+
+            approvals.insert(
+                spender,
+                arg.approval_info.created_at_time.unwrap_or_default() / SECOND,
+                arg.approval_info.expires_at.unwrap_or_default() / SECOND,
+            );
+
+            let tx_log = Transaction::approve_collection(
+                now,
+                Account::from(from),
+                Account::from(spender),
+                arg.approval_info.expires_at,
+                arg.approval_info.memo.to_owned(),
+            )
+            .with_created_at_time(arg.approval_info.created_at_time);
+
+            match store::blocks::append(tx_log) {
+                Ok(idx) => {
+                    res[index] = Some(Ok(Nat::from(idx)));
+                    if let Some((hash, ts)) = dedup {
+                        store::tx_dedup::insert(hash, ts, idx);
+                    }
+                    r.insert(from, approvals);
                 }
-                if total >= settings.max_approvals_per_token_or_collection as u32 {
+                Err(err) => {
                     res[index] = Some(Err(ApproveCollectionError::GenericBatchError {
                         error_code: Nat::from(0u64),
-                        message: "exceeds the maximum number of approvals".to_string(),
+                        message: err,
                     }));
-                    continue;
-                }
-
-                approvals.insert(
-                    arg.approval_info.spender.owner,
-                    arg.approval_info.created_at_time.unwrap_or_default() / SECOND,
-                    arg.approval_info.expires_at.unwrap_or_default() / SECOND,
-                );
-                total += 1;
-
-                let tx_log = Transaction::approve_collection(
-                    now,
-                    caller,
-                    arg.approval_info.spender.owner,
-                    arg.approval_info.expires_at,
-                    arg.approval_info.memo.to_owned(),
-                );
-
-                match store::blocks::append(tx_log) {
-                    Ok(idx) => {
-                        res[index] = Some(Ok(Nat::from(idx)));
-                    }
-                    Err(err) => {
-                        res[index] = Some(Err(ApproveCollectionError::GenericBatchError {
-                            error_code: Nat::from(0u64),
-                            message: err,
-                        }));
-                        r.insert(caller, approvals);
-                        // break up when append log failed.
-                        return res;
-                    }
+                    // break up when append log failed.
+                    return res;
                 }
             }
-
-            r.insert(caller, approvals);
         }
 
         res
@@ -310,52 +359,52 @@ pub fn icrc37_revoke_token_approvals(
     store::holder_tokens::with_mut(|r| {
         let mut res: Vec<Option<RevokeTokenApprovalResult>> = vec![None; args.len()];
         let now = ic_cdk::api::time();
-        match r.get(&caller) {
-            None => {
-                res.fill(Some(Err(RevokeTokenApprovalError::Unauthorized)));
+        for (index, arg) in args.iter().enumerate() {
+            if let Err(err) = arg.validate(now, &caller, &settings) {
+                res[index] = Some(Err(err));
+                continue;
             }
-            Some(mut tokens) => {
-                for (index, arg) in args.iter().enumerate() {
-                    if let Err(err) = arg.validate(now, &caller, &settings) {
-                        res[index] = Some(Err(err));
-                        continue;
-                    }
 
-                    let id = SftId::from(&arg.token_id);
-                    let spender = arg.spender.map(|s| s.owner);
-                    match tokens.revoke(id.0, id.1, spender) {
-                        Err(err) => {
-                            res[index] = Some(Err(err));
+            let from = AccountKey::new(caller, arg.from_subaccount);
+            let mut tokens = match r.get(&from) {
+                Some(tokens) => tokens,
+                None => {
+                    res[index] = Some(Err(RevokeTokenApprovalError::Unauthorized));
+                    continue;
+                }
+            };
+
+            let id = SftId::from(&arg.token_id);
+            let spender = arg.spender.as_ref().map(AccountKey::from);
+            match tokens.revoke(id.0, id.1, spender) {
+                Err(err) => {
+                    res[index] = Some(Err(err));
+                }
+                Ok(_) => {
+                    let tx_log = Transaction::revoke(
+                        now,
+                        id.to_u64(),
+                        Account::from(from),
+                        spender.map(Account::from),
+                        arg.memo.to_owned(),
+                    )
+                    .with_created_at_time(arg.created_at_time);
+
+                    match store::blocks::append(tx_log) {
+                        Ok(idx) => {
+                            res[index] = Some(Ok(Nat::from(idx)));
+                            r.insert(from, tokens);
                         }
-                        Ok(_) => {
-                            let tx_log = Transaction::revoke(
-                                now,
-                                id.to_u64(),
-                                caller,
-                                spender,
-                                arg.memo.to_owned(),
-                            );
-
-                            match store::blocks::append(tx_log) {
-                                Ok(idx) => {
-                                    res[index] = Some(Ok(Nat::from(idx)));
-                                }
-                                Err(err) => {
-                                    res[index] =
-                                        Some(Err(RevokeTokenApprovalError::GenericBatchError {
-                                            error_code: Nat::from(0u64),
-                                            message: err,
-                                        }));
-                                    r.insert(caller, tokens);
-                                    // break up when append log failed.
-                                    return res;
-                                }
-                            }
+                        Err(err) => {
+                            res[index] = Some(Err(RevokeTokenApprovalError::GenericBatchError {
+                                error_code: Nat::from(0u64),
+                                message: err,
+                            }));
+                            // break up when append log failed.
+                            return res;
                         }
                     }
                 }
-
-                r.insert(caller, tokens);
             }
         }
 
@@ -379,47 +428,39 @@ pub fn icrc37_revoke_collection_approvals(
         ic_cdk::trap("exceeds max revoke approvals");
     }
     let now = ic_cdk::api::time();
-    let mut idxs: Vec<usize> = Vec::new();
-    let mut spenders: Vec<Option<Principal>> = Vec::new();
-    let mut res: Vec<Option<RevokeCollectionApprovalResult>> = vec![None; spenders.len()];
-    for (i, arg) in args.iter().enumerate() {
+    let mut res: Vec<Option<RevokeCollectionApprovalResult>> = vec![None; args.len()];
+    for (index, arg) in args.iter().enumerate() {
         if let Err(err) = arg.validate(now, &caller, &settings) {
-            res[i] = Some(Err(err));
+            res[index] = Some(Err(err));
             continue;
         }
 
-        idxs.push(i);
-        spenders.push(arg.spender.map(|s| s.owner));
-    }
+        let from = AccountKey::new(caller, arg.from_subaccount);
+        let spender = arg.spender.as_ref().map(AccountKey::from);
+        if let Err(err) = store::approvals::revoke(&from, spender.as_ref()) {
+            res[index] = Some(Err(err));
+            continue;
+        }
 
-    let res2 = store::approvals::revoke(&caller, &spenders);
-    for (i, idx) in idxs.into_iter().enumerate() {
-        match res2[i] {
-            Some(ref val) => {
-                // some error
-                res[idx] = Some(val.to_owned());
+        let tx_log = Transaction::revoke_collection(
+            now,
+            Account::from(from),
+            spender.map(Account::from),
+            arg.memo.to_owned(),
+        )
+        .with_created_at_time(arg.created_at_time);
+
+        match store::blocks::append(tx_log) {
+            Ok(block_idx) => {
+                res[index] = Some(Ok(Nat::from(block_idx)));
             }
-            None => {
-                let tx_log = Transaction::revoke_collection(
-                    now,
-                    caller,
-                    spenders[i],
-                    args[idx].memo.to_owned(),
-                );
-
-                match store::blocks::append(tx_log) {
-                    Ok(block_idx) => {
-                        res[idx] = Some(Ok(Nat::from(block_idx)));
-                    }
-                    Err(err) => {
-                        res[idx] = Some(Err(RevokeCollectionApprovalError::GenericBatchError {
-                            error_code: Nat::from(0u64),
-                            message: err,
-                        }));
-                        // break up when append log failed.
-                        // return res; // TODO: uncomment this line
-                    }
-                }
+            Err(err) => {
+                res[index] = Some(Err(RevokeCollectionApprovalError::GenericBatchError {
+                    error_code: Nat::from(0u64),
+                    message: err,
+                }));
+                // break up when append log failed.
+                return res;
             }
         }
     }
@@ -439,6 +480,16 @@ pub fn icrc37_transfer_from(args: Vec<TransferFromArg>) -> Vec<Option<TransferFr
         ic_cdk::trap("exceeds max update batch size");
     }
 
+    if store::collection::with(|c| c.paused.transfer_from) {
+        return vec![
+            Some(Err(TransferFromError::GenericError {
+                error_code: Nat::from(0u64),
+                message: "transfer_from is paused".to_string(),
+            }));
+            args.len()
+        ];
+    }
+
     let caller = ic_cdk::caller();
     let now = ic_cdk::api::time();
     let now_sec = now / SECOND;
@@ -450,17 +501,38 @@ pub fn icrc37_transfer_from(args: Vec<TransferFromArg>) -> Vec<Option<TransferFr
             ic_cdk::trap(format!("invalid transfer from args: {:?}", err).as_str())
         }
 
-        let query: Vec<(SftId, &Principal)> = args
+        let hashes: Vec<[u8; 32]> = args
             .iter()
-            .map(|arg| (SftId::from(&arg.token_id), &arg.from.owner))
+            .filter(|arg| arg.created_at_time.is_some())
+            .map(|arg| tx_hash("37xfer", &caller, arg))
             .collect();
+        match store::tx_dedup::check_batch(&hashes, now, &settings) {
+            Ok(()) => {}
+            Err(Some(idx)) => {
+                let err = TransferFromError::Duplicate {
+                    duplicate_of: Nat::from(idx),
+                };
+                ic_cdk::trap(format!("invalid transfer from args: {:?}", err).as_str())
+            }
+            Err(None) => {
+                ic_cdk::trap("invalid transfer from args: duplicate transfers in the batch")
+            }
+        }
 
-        let query = store::approvals::find_unapproved(&caller, &query, now_sec);
-
-        if let Err(from) = store::holder_tokens::all_is_approved(&caller, &query, now_sec) {
-            ic_cdk::trap(
-                format!("(from: {}, spender: {}) are not approved", from, caller).as_str(),
-            );
+        for arg in &args {
+            let spender = AccountKey::new(caller, arg.spender_subaccount);
+            let query = [(SftId::from(&arg.token_id), AccountKey::from(&arg.from))];
+            let query = store::approvals::find_unapproved(&spender, &query, now_sec);
+            if let Err(from) = store::holder_tokens::all_is_approved(&spender, &query, now_sec) {
+                ic_cdk::trap(
+                    format!(
+                        "(from: {}, spender: {}) are not approved",
+                        Account::from(*from),
+                        Account::from(spender)
+                    )
+                    .as_str(),
+                );
+            }
         }
     }
 
@@ -472,9 +544,22 @@ pub fn icrc37_transfer_from(args: Vec<TransferFromArg>) -> Vec<Option<TransferFr
                 continue;
             }
 
+            let dedup = arg
+                .created_at_time
+                .map(|ts| (tx_hash("37xfer", &caller, arg), ts));
+            if let Some(idx) = dedup.and_then(|(h, _)| store::tx_dedup::find(&h, now, &settings)) {
+                res[index] = Some(Err(TransferFromError::Duplicate {
+                    duplicate_of: Nat::from(idx),
+                }));
+                continue;
+            }
+
             let id = SftId::from(&arg.token_id);
-            if !store::approvals::is_approved(&arg.from.owner, &caller, now_sec)
-                && !store::holder_tokens::is_approved(&arg.from.owner, &caller, id.0, id.1, now_sec)
+            let from = AccountKey::from(&arg.from);
+            let to = AccountKey::from(&arg.to);
+            let spender = AccountKey::new(caller, arg.spender_subaccount);
+            if !store::approvals::is_approved(&from, &spender, now_sec)
+                && !store::holder_tokens::is_approved(&from, &spender, id.0, id.1, now_sec)
             {
                 res[index] = Some(Err(TransferFromError::Unauthorized));
                 continue;
@@ -484,44 +569,41 @@ pub fn icrc37_transfer_from(args: Vec<TransferFromArg>) -> Vec<Option<TransferFr
                 None => {
                     res[index] = Some(Err(TransferFromError::NonExistingTokenId));
                 }
-                Some(mut holders) => {
-                    match holders.transfer_from(&arg.from.owner, &arg.to.owner, id.1) {
-                        Ok(_) => {
-                            let tx_log = Transaction::transfer_from(
-                                now,
-                                id.to_u64(),
-                                arg.from.owner,
-                                arg.to.owner,
-                                caller,
-                                arg.memo.clone(),
-                            );
-
-                            match store::blocks::append(tx_log) {
-                                Ok(idx) => {
-                                    res[index] = Some(Ok(Nat::from(idx)));
-                                    r.insert(id.0, holders);
-                                    store::holder_tokens::update_for_transfer(
-                                        caller,
-                                        arg.to.owner,
-                                        id.0,
-                                        id.1,
-                                    );
-                                }
-                                Err(err) => {
-                                    res[index] = Some(Err(TransferFromError::GenericBatchError {
-                                        error_code: Nat::from(0u64),
-                                        message: err,
-                                    }));
-                                    // break up when append log failed.
-                                    return res;
+                Some(mut holders) => match holders.transfer_from(&from, &to, id.1) {
+                    Ok(_) => {
+                        let tx_log = Transaction::transfer_from(
+                            now,
+                            id.to_u64(),
+                            Account::from(from),
+                            Account::from(to),
+                            Account::from(spender),
+                            arg.memo.clone(),
+                        )
+                        .with_created_at_time(arg.created_at_time);
+
+                        match store::blocks::append(tx_log) {
+                            Ok(idx) => {
+                                res[index] = Some(Ok(Nat::from(idx)));
+                                if let Some((hash, ts)) = dedup {
+                                    store::tx_dedup::insert(hash, ts, idx);
                                 }
+                                r.insert(id.0, holders);
+                                store::holder_tokens::update_for_transfer(from, to, id.0, id.1);
+                            }
+                            Err(err) => {
+                                res[index] = Some(Err(TransferFromError::GenericBatchError {
+                                    error_code: Nat::from(0u64),
+                                    message: err,
+                                }));
+                                // break up when append log failed.
+                                return res;
                             }
-                        }
-                        Err(err) => {
-                            res[index] = Some(Err(err));
                         }
                     }
-                }
+                    Err(err) => {
+                        res[index] = Some(Err(err));
+                    }
+                },
             }
         }
 
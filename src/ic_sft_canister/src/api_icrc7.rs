@@ -1,4 +1,9 @@
-use crate::{is_authenticated, schema::Validate, store};
+use crate::{
+    is_authenticated,
+    schema::Validate,
+    store::{self, AccountKey},
+    utils::tx_hash,
+};
 use candid::Nat;
 use ic_sft_types::{
     nat_to_u64, Metadata, SftId, Transaction, TransferArg, TransferError, TransferResult,
@@ -35,10 +40,11 @@ pub fn icrc7_logo() -> Option<String> {
     store::collection::with(|c| c.logo.clone())
 }
 
-// Returns the total number of NFTs on all accounts.
+// Returns the total number of NFTs on all accounts, that is the units in circulation.
+// The number of token types is exposed as "sft:token_types" in the collection metadata.
 #[ic_cdk::query]
 pub fn icrc7_total_supply() -> Nat {
-    store::collection::with(|c| c.total_supply.into())
+    store::collection::with(|c| c.circulating_supply().into())
 }
 
 // Returns the maximum number of NFTs possible for this collection. Any attempt to mint more NFTs
@@ -136,12 +142,8 @@ pub fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
             .iter()
             .map(|id| {
                 let id = SftId::from(id);
-                r.get(&id.0).and_then(|hs| {
-                    hs.get(id.1).map(|h| Account {
-                        owner: *h,
-                        subaccount: None,
-                    })
-                })
+                r.get(&id.0)
+                    .and_then(|hs| hs.get(id.1).map(|h| Account::from(*h)))
             })
             .collect()
     })
@@ -163,7 +165,7 @@ pub fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
         let res: Vec<Nat> = accounts
             .into_iter()
             .map(|acc| {
-                r.get(&acc.owner)
+                r.get(&AccountKey::from(&acc))
                     .map(|tokens| tokens.balance_of())
                     .unwrap_or(0u64)
             })
@@ -202,7 +204,7 @@ pub fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -
     let take = store::collection::take_value(take.as_ref().map(nat_to_u64));
 
     store::holder_tokens::with(|r| {
-        r.get(&account.owner)
+        r.get(&AccountKey::from(&account))
             .map(|tokens| {
                 let SftId(start_tid, mut start_sid) = if let Some(ref prev) = prev {
                     SftId::from(prev).next()
@@ -249,6 +251,16 @@ pub fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<TransferResult>> {
         ic_cdk::trap("exceeds max update batch size");
     }
 
+    if store::collection::with(|c| c.paused.transfers) {
+        return vec![
+            Some(Err(TransferError::GenericError {
+                error_code: Nat::from(0u64),
+                message: "transfers are paused".to_string(),
+            }));
+            args.len()
+        ];
+    }
+
     let caller = ic_cdk::caller();
     let now = ic_cdk::api::time();
     if settings.atomic_batch_transfers && args.len() > 1 {
@@ -259,13 +271,30 @@ pub fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<TransferResult>> {
             ic_cdk::trap(format!("invalid transfer args: {:?}", err).as_str())
         }
 
+        let hashes: Vec<[u8; 32]> = args
+            .iter()
+            .filter(|arg| arg.created_at_time.is_some())
+            .map(|arg| tx_hash("7xfer", &caller, arg))
+            .collect();
+        match store::tx_dedup::check_batch(&hashes, now, &settings) {
+            Ok(()) => {}
+            Err(Some(idx)) => {
+                let err = TransferError::Duplicate {
+                    duplicate_of: Nat::from(idx),
+                };
+                ic_cdk::trap(format!("invalid transfer args: {:?}", err).as_str())
+            }
+            Err(None) => ic_cdk::trap("invalid transfer args: duplicate transfers in the batch"),
+        }
+
         if let Err(err) = store::holders::with(|r| {
             for arg in &args {
                 let id = SftId::from(&arg.token_id);
                 match r.get(&id.0) {
                     None => return Err(TransferError::NonExistingTokenId),
                     Some(ref holders) => {
-                        if !holders.is_holder(id.1, &caller) {
+                        let from = AccountKey::new(caller, arg.from_subaccount);
+                        if !holders.is_holder(id.1, &from) {
                             return Err(TransferError::Unauthorized);
                         }
                     }
@@ -285,31 +314,42 @@ pub fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<TransferResult>> {
                 continue;
             }
 
+            let dedup = arg
+                .created_at_time
+                .map(|ts| (tx_hash("7xfer", &caller, arg), ts));
+            if let Some(idx) = dedup.and_then(|(h, _)| store::tx_dedup::find(&h, now, &settings)) {
+                res[index] = Some(Err(TransferError::Duplicate {
+                    duplicate_of: Nat::from(idx),
+                }));
+                continue;
+            }
+
             let id = SftId::from(&arg.token_id);
+            let from = AccountKey::new(caller, arg.from_subaccount);
+            let to = AccountKey::from(&arg.to);
             match r.get(&id.0) {
                 None => {
                     res[index] = Some(Err(TransferError::NonExistingTokenId));
                 }
-                Some(mut holders) => match holders.transfer_to(&caller, &arg.to.owner, id.1) {
+                Some(mut holders) => match holders.transfer_to(&from, &to, id.1) {
                     Ok(_) => {
                         let tx_log = Transaction::transfer(
                             now,
                             id.to_u64(),
-                            caller,
-                            arg.to.owner,
+                            Account::from(from),
+                            Account::from(to),
                             arg.memo.clone(),
-                        );
+                        )
+                        .with_created_at_time(arg.created_at_time);
 
                         match store::blocks::append(tx_log) {
                             Ok(idx) => {
                                 res[index] = Some(Ok(Nat::from(idx)));
+                                if let Some((hash, ts)) = dedup {
+                                    store::tx_dedup::insert(hash, ts, idx);
+                                }
                                 r.insert(id.0, holders);
-                                store::holder_tokens::update_for_transfer(
-                                    caller,
-                                    arg.to.owner,
-                                    id.0,
-                                    id.1,
-                                );
+                                store::holder_tokens::update_for_transfer(from, to, id.0, id.1);
                             }
                             Err(err) => {
                                 res[index] = Some(Err(TransferError::GenericBatchError {
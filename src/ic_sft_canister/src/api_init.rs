@@ -1,7 +1,10 @@
-use crate::{store, SECOND};
+use crate::{api_sft_http, archive, store, sweeper, SECOND};
 use ic_sft_types::InitArg;
 use std::time::Duration;
 
+const ARCHIVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const SWEEP_APPROVALS_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
 #[ic_cdk::init]
 pub fn init(args: InitArg) {
     let now = ic_cdk::api::time() / SECOND;
@@ -25,13 +28,24 @@ pub fn init(args: InitArg) {
         r.settings.max_approvals_per_token_or_collection =
             args.max_approvals_per_token_or_collection.unwrap_or(10);
         r.settings.max_revoke_approvals = args.max_revoke_approvals.unwrap_or(10);
+        r.settings.managers_can_burn = args.managers_can_burn.unwrap_or(false);
+        r.settings.archive_trigger_threshold = args.archive_trigger_threshold.unwrap_or(10_000);
+        r.settings.max_blocks_per_archive = args.max_blocks_per_archive.unwrap_or(1_000_000);
+        r.settings.archive_cycles = args.archive_cycles.unwrap_or(2_000_000_000_000);
+        r.indexes = store::Index::ALL.into();
     });
 
     store::collection::save();
+    api_sft_http::certify_routes();
     ic_cdk::api::set_certified_data(&store::collection::with(|r| r.root_hash()));
     ic_cdk_timers::set_timer(Duration::from_nanos(0), || {
         ic_cdk::spawn(store::keys::load())
     });
+    ic_cdk_timers::set_timer_interval(
+        ARCHIVE_INTERVAL,
+        || ic_cdk::spawn(archive::archive_blocks()),
+    );
+    ic_cdk_timers::set_timer_interval(SWEEP_APPROVALS_INTERVAL, sweeper::sweep_expired_approvals);
 }
 
 #[ic_cdk::pre_upgrade]
@@ -43,8 +57,20 @@ pub fn pre_upgrade() {
 #[ic_cdk::post_upgrade]
 pub fn post_upgrade() {
     store::collection::load();
+    store::collection::init_roles();
+    store::indexes::start();
+    api_sft_http::certify_routes();
+    ic_cdk::api::set_certified_data(&store::collection::with(|r| r.root_hash()));
 
     ic_cdk_timers::set_timer(Duration::from_nanos(0), || {
         ic_cdk::spawn(store::keys::load())
     });
+    // the tokens are certified and indexed in bounded steps
+    ic_cdk_timers::set_timer(Duration::from_nanos(0), sweeper::certify_tokens);
+    ic_cdk_timers::set_timer(Duration::from_nanos(0), sweeper::rebuild_indexes);
+    ic_cdk_timers::set_timer_interval(
+        ARCHIVE_INTERVAL,
+        || ic_cdk::spawn(archive::archive_blocks()),
+    );
+    ic_cdk_timers::set_timer_interval(SWEEP_APPROVALS_INTERVAL, sweeper::sweep_expired_approvals);
 }
@@ -0,0 +1,953 @@
+use crate::{
+    store::{self, assets::ASSET_CHUNK_SIZE},
+    utils::{sha3_256, to_cbor_bytes, to_json},
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use candid::{CandidType, Func};
+use ic_certification::HashTree;
+use ic_sft_types::{Metadata, SftId, Value};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+
+static JSON_CONTENT_TYPE: &str = "application/json; charset=utf-8";
+
+// The CEL expression for response verification v2, the body and the listed headers are certified.
+static CERT_EXPR: &str = "default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{certified_response_headers:ResponseHeaderList{headers:[\"content-type\",\"etag\",\"content-range\",\"content-encoding\"]}}}})";
+
+// The CEL expression of the redirects, the "location" header is certified.
+static REDIRECT_CERT_EXPR: &str = "default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{certified_response_headers:ResponseHeaderList{headers:[\"location\"]}}}})";
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
+    pub certificate_version: Option<u16>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
+    pub streaming_strategy: Option<StreamingStrategy>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: Func,
+        token: StreamingCallbackToken,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct StreamingCallbackToken {
+    pub asset_hash: [u8; 32],             // the asset served by the response
+    pub index: u32,                       // the index of the next chunk
+    pub content_encoding: Option<String>, // the encoded variant of the asset for an encoded response
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: ByteBuf,
+    pub token: Option<StreamingCallbackToken>,
+}
+
+impl HttpResponse {
+    fn text(status_code: u16, message: &str) -> Self {
+        HttpResponse {
+            status_code,
+            headers: vec![(
+                "content-type".to_string(),
+                "text/plain; charset=utf-8".to_string(),
+            )],
+            body: ByteBuf::from(message.as_bytes()),
+            streaming_strategy: None,
+        }
+    }
+}
+
+struct Asset {
+    path: Vec<String>,
+    content_type: String,
+    hash: [u8; 32],
+    cache_control: &'static str,
+}
+
+// Serves the token assets and metadata over HTTP:
+//   GET /token/{token_id}, the asset of the token type.
+//   GET /token/{token_id}/{slot}, the named asset of the token type, such as "thumbnail" or "animation".
+//   GET /asset/{sha3_256_hex}, the asset with the SHA3-256 hash.
+//   GET /metadata/{token_id}.json, the JSON metadata of the token type.
+//   GET /collection.json, the JSON metadata of the collection.
+// A SFT id in place of the `token_id` is redirected (308) to the path of its token type id,
+// the "<*>" wildcard of the route certifies the redirects to every token type path with their "location".
+// The 404 responses are certified by the "<*>" wildcard of the route or by the root fallback.
+// The responses are certified (response verification v2), a HEAD request is served the certified GET response
+// and the gateway drops its body. A 304 response is certified for each path with the headers of its 200 response.
+// A single byte range in the "Range" header is served as 206 Partial Content with the whole stored chunk
+// containing its first byte, so that each range response is a certified chunk, "content-range" tells the bytes served.
+// The assets larger than a chunk are streamed by http_request_streaming_callback.
+// The assets stored before chunking are served uncertified until they are moved into chunks.
+// The gzip or br variant of the asset is served if it is accepted by the "Accept-Encoding" header,
+// each variant is certified with its "content-encoding" header and its encoded body.
+#[ic_cdk::query]
+pub fn http_request(req: HttpRequest) -> HttpResponse {
+    if !req.method.eq_ignore_ascii_case("HEAD") && !req.method.eq_ignore_ascii_case("GET") {
+        return HttpResponse::text(405, "method not allowed");
+    }
+
+    let path = req.url.split(['?', '#']).next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let asset = match segments.as_slice() {
+        ["collection.json"] => {
+            return json_response(&req, collection_json(), &segments);
+        }
+        ["metadata", name] => {
+            let token = name
+                .strip_suffix(".json")
+                .and_then(parse_token_id)
+                .and_then(|id| Some((id.0, get_token(id.1)?)));
+            return match token {
+                Some((id, token)) if id != token.id as u64 => {
+                    redirect_response(format!("/metadata/{}.json", token.id), &segments)
+                }
+                Some((_, token)) => json_response(&req, token_json(&token), &segments),
+                None => not_found(&segments),
+            };
+        }
+        ["token", id] | ["token", id, _] => {
+            let slot = segments.get(2).copied();
+            match parse_token_id(id) {
+                // only the redirects to the existing paths are certified
+                Some((id, tid)) if id != tid as u64 => match get_token(tid) {
+                    Some(token) if slot.map_or(true, |slot| token.assets.contains_key(slot)) => {
+                        return redirect_response(token_location(tid, slot), &segments);
+                    }
+                    _ => None,
+                },
+                Some((_, tid)) => find_token_asset(tid, slot),
+                None => None,
+            }
+        }
+        ["asset", hash] => find_asset(hash),
+        _ => None,
+    };
+
+    let asset = match asset {
+        Some(asset) => asset,
+        None => return not_found(&segments),
+    };
+
+    let path: Vec<&str> = asset.path.iter().map(|s| s.as_str()).collect();
+    let mut headers = certified_headers(&asset.content_type, &asset.hash);
+    headers.push(("cache-control".to_string(), asset.cache_control.to_string()));
+    headers.push(("access-control-allow-origin".to_string(), "*".to_string()));
+    headers.push(("accept-ranges".to_string(), "bytes".to_string()));
+    if let Some(res) = not_modified(&req, &headers, &path) {
+        return res;
+    }
+
+    let meta = store::assets::meta(&asset.hash);
+    let size = match meta
+        .as_ref()
+        .map(|meta| meta.size)
+        .or_else(|| store::assets::size(&asset.hash))
+    {
+        Some(size) => size,
+        None => return not_found(&segments),
+    };
+
+    let range = header_value(&req.headers, "range").map(|value| parse_range(value, size));
+    match range {
+        Some(Ok(Some((start, _)))) => {
+            let (index, start, end) = served_range(start, size);
+            headers.push(("content-range".to_string(), content_range(start, end, size)));
+            headers.push(("content-length".to_string(), (end - start).to_string()));
+            push_certificate(&mut headers, &path);
+            let body = store::assets::chunk(&asset.hash, index).unwrap_or_default();
+            return HttpResponse {
+                status_code: 206,
+                headers,
+                body: ByteBuf::from(body),
+                streaming_strategy: None,
+            };
+        }
+        Some(Err(_)) => {
+            let mut res = HttpResponse::text(416, "range not satisfiable");
+            res.headers
+                .push(("content-range".to_string(), format!("bytes */{}", size)));
+            return res;
+        }
+        // multiple ranges or no range, serve the whole asset
+        _ => {}
+    }
+
+    headers.push(("vary".to_string(), "accept-encoding".to_string()));
+    // the encoded variant is served from its own asset
+    let (hash, encoding, size) = match meta
+        .as_ref()
+        .and_then(|meta| accepted_encoding(&req.headers, meta))
+    {
+        Some((name, encoded)) => match store::assets::size(&encoded) {
+            Some(size) => {
+                headers.push(("content-encoding".to_string(), name.to_string()));
+                (encoded, Some(name), size)
+            }
+            None => (asset.hash, None, size),
+        },
+        None => (asset.hash, None, size),
+    };
+
+    headers.push(("content-length".to_string(), size.to_string()));
+    push_certificate(&mut headers, &path);
+    let body = store::assets::chunk(&hash, 0).unwrap_or_default();
+    let token = next_token(asset.hash, encoding.map(String::from), 0, size);
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body: ByteBuf::from(body),
+        streaming_strategy: token.map(|token| StreamingStrategy::Callback {
+            callback: Func {
+                principal: ic_cdk::id(),
+                method: "http_request_streaming_callback".to_string(),
+            },
+            token,
+        }),
+    }
+}
+
+// Returns the next chunk of the asset for a streaming response.
+// Only the assets served by "/asset/{hash}" are streamed, with the encoded variant of the original response.
+#[ic_cdk::query]
+pub fn http_request_streaming_callback(
+    token: StreamingCallbackToken,
+) -> StreamingCallbackHttpResponse {
+    let asset = find_asset(&hex::encode(token.asset_hash))
+        .unwrap_or_else(|| ic_cdk::trap("asset not found"));
+    let hash = match token.content_encoding {
+        None => asset.hash,
+        Some(ref name) => store::assets::encoding_code(name)
+            .and_then(|code| {
+                let meta = store::assets::meta(&asset.hash)?;
+                meta.encodings.get(&code).copied()
+            })
+            .unwrap_or_else(|| ic_cdk::trap("asset encoding not found")),
+    };
+
+    let body = store::assets::chunk(&hash, token.index)
+        .unwrap_or_else(|| ic_cdk::trap("asset chunk not found"));
+    let size = store::assets::size(&hash).unwrap_or(0);
+    StreamingCallbackHttpResponse {
+        body: ByteBuf::from(body),
+        token: next_token(token.asset_hash, token.content_encoding, token.index, size),
+    }
+}
+
+// Returns the token of the chunk after `index`, None if it is the last chunk.
+// `size` is the size of the content served, the encoded content for an encoded response.
+fn next_token(
+    asset_hash: [u8; 32],
+    content_encoding: Option<String>,
+    index: u32,
+    size: u64,
+) -> Option<StreamingCallbackToken> {
+    let index = index + 1;
+    if index as u64 * ASSET_CHUNK_SIZE as u64 >= size {
+        return None;
+    }
+    Some(StreamingCallbackToken {
+        asset_hash,
+        index,
+        content_encoding,
+    })
+}
+
+// Returns the index and the byte range [start, end) of the chunk containing the first byte of the range.
+fn served_range(start: u64, size: u64) -> (u32, u64, u64) {
+    let index = (start / ASSET_CHUNK_SIZE as u64) as u32;
+    let (start, end) = chunk_range(index, size);
+    (index, start, end)
+}
+
+// Returns the byte range [start, end) of the chunk at `index`.
+fn chunk_range(index: u32, size: u64) -> (u64, u64) {
+    let start = index as u64 * ASSET_CHUNK_SIZE as u64;
+    (start, (start + ASSET_CHUNK_SIZE as u64).min(size))
+}
+
+// The "content-range" header value of the byte range [start, end).
+fn content_range(start: u64, end: u64, size: u64) -> String {
+    format!("bytes {}-{}/{}", start, end - 1, size)
+}
+
+// Returns the preferred encoded variant of the asset that is accepted by the "Accept-Encoding" header.
+fn accepted_encoding(
+    headers: &[(String, String)],
+    meta: &store::AssetMeta,
+) -> Option<(&'static str, [u8; 32])> {
+    if meta.encodings.is_empty() {
+        return None;
+    }
+
+    let accept = header_value(headers, "accept-encoding")?.to_ascii_lowercase();
+    let accepted: Vec<&str> = accept
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let name = params.next()?.trim();
+            // "q=0" means not acceptable
+            let rejected = params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            if rejected {
+                None
+            } else {
+                Some(name)
+            }
+        })
+        .collect();
+
+    store::assets::CONTENT_ENCODINGS
+        .into_iter()
+        .filter(|(_, name)| accepted.contains(name))
+        .find_map(|(code, name)| meta.encodings.get(&code).map(|hash| (name, *hash)))
+}
+
+// Parses the "Range" header value, returns the inclusive byte range of "bytes=start-end",
+// "bytes=start-" or "bytes=-suffix", Ok(None) for multiple or unknown ranges, Err if it is not satisfiable.
+fn parse_range(value: &str, total: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return Ok(None),
+    };
+
+    let (start, end) = spec.trim().split_once('-').ok_or(())?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().map_err(|_| ())?;
+            if suffix == 0 {
+                return Err(());
+            }
+            (total.saturating_sub(suffix), total.saturating_sub(1))
+        }
+        (start, "") => (start.parse().map_err(|_| ())?, total.saturating_sub(1)),
+        (start, end) => {
+            let start: u64 = start.parse().map_err(|_| ())?;
+            let end: u64 = end.parse().map_err(|_| ())?;
+            (start, end.min(total.saturating_sub(1)))
+        }
+    };
+
+    if start >= total || start > end {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+// Certifies the responses of "/token/{id}", "/token/{id}/{slot}" and "/asset/{asset_hash}" for the token,
+// the responses are computed from the stored asset hashes without reading the contents.
+pub fn certify_token(token: &store::Token) {
+    let id = token.id.to_string();
+    match store::assets::meta(&token.asset_hash) {
+        Some(meta) => {
+            let responses = asset_responses(&token.asset_content_type, &token.asset_hash, &meta);
+            store::http_tree::insert(
+                &["token", id.as_str()],
+                false,
+                expr_hash(CERT_EXPR),
+                &responses,
+            );
+            certify_asset(&token.asset_hash, &meta);
+        }
+        None => store::http_tree::remove(&["token", id.as_str()]),
+    }
+    // a replaced asset of the slot is uncertified by the insertion
+    for (slot, asset) in &token.assets {
+        let path = ["token", id.as_str(), slot.as_str()];
+        match store::assets::meta(&asset.hash) {
+            Some(meta) => {
+                let responses = asset_responses(&asset.content_type, &asset.hash, &meta);
+                store::http_tree::insert(&path, false, expr_hash(CERT_EXPR), &responses);
+                certify_asset(&asset.hash, &meta);
+            }
+            None => store::http_tree::remove(&path),
+        }
+    }
+    certify_metadata(token);
+    certify_redirects(token);
+}
+
+// Certifies the redirects from the SFT id paths to the paths of the token type,
+// they are added to the wildcards of the routes and never removed as the token types and slots are kept.
+fn certify_redirects(token: &store::Token) {
+    let expr = expr_hash(REDIRECT_CERT_EXPR);
+    let mut locations = vec![token_location(token.id, None)];
+    locations.extend(
+        token
+            .assets
+            .keys()
+            .map(|slot| token_location(token.id, Some(slot.as_str()))),
+    );
+    for location in locations {
+        store::http_tree::insert_response(&["token"], true, expr, redirect_hash(&location));
+    }
+    let location = format!("/metadata/{}.json", token.id);
+    store::http_tree::insert_response(&["metadata"], true, expr, redirect_hash(&location));
+}
+
+// The responses of the asset: the whole content, the 304 response, each encoded variant,
+// and each chunk as a range response.
+fn asset_responses(content_type: &str, hash: &[u8; 32], meta: &store::AssetMeta) -> Vec<[u8; 32]> {
+    let headers = certified_headers(content_type, hash);
+    let mut res = vec![
+        response_hash(CERT_EXPR, 200, &headers, &meta.sha256),
+        response_hash(CERT_EXPR, 304, &headers, &sha256(b"")),
+    ];
+    for (code, encoded) in &meta.encodings {
+        let name = store::assets::CONTENT_ENCODINGS
+            .into_iter()
+            .find(|(c, _)| c == code)
+            .map(|(_, name)| name);
+        if let (Some(name), Some(encoded)) = (name, store::assets::meta(encoded)) {
+            let mut headers = headers.clone();
+            headers.push(("content-encoding".to_string(), name.to_string()));
+            res.push(response_hash(CERT_EXPR, 200, &headers, &encoded.sha256));
+        }
+    }
+    for (index, chunk_hash) in meta.chunks.iter().enumerate() {
+        let (start, end) = chunk_range(index as u32, meta.size);
+        let mut headers = headers.clone();
+        headers.push((
+            "content-range".to_string(),
+            content_range(start, end, meta.size),
+        ));
+        res.push(response_hash(CERT_EXPR, 206, &headers, chunk_hash));
+    }
+    res
+}
+
+// The asset is served with its stored content type, it is certified once for the tokens sharing it.
+fn certify_asset(hash: &[u8; 32], meta: &store::AssetMeta) {
+    let key = hex::encode(hash);
+    if !store::http_tree::contains(&["asset", key.as_str()], false) {
+        let responses = asset_responses(&meta.content_type, hash, meta);
+        store::http_tree::insert(
+            &["asset", key.as_str()],
+            false,
+            expr_hash(CERT_EXPR),
+            &responses,
+        );
+    }
+}
+
+// Certifies the response of "/metadata/{id}.json" for the token.
+pub fn certify_metadata(token: &store::Token) {
+    let name = format!("{}.json", token.id);
+    certify_json(&["metadata", name.as_str()], &token_json(token));
+}
+
+// Certifies the response of "/collection.json", called when the collection is changed.
+pub fn certify_collection() {
+    certify_json(&["collection.json"], &collection_json());
+}
+
+fn certify_json(path: &[&str], body: &str) {
+    let headers = certified_headers(JSON_CONTENT_TYPE, &sha3_256(body.as_bytes()));
+    let responses = [
+        response_hash(CERT_EXPR, 200, &headers, &sha256(body.as_bytes())),
+        response_hash(CERT_EXPR, 304, &headers, &sha256(b"")),
+    ];
+    store::http_tree::insert(path, false, expr_hash(CERT_EXPR), &responses);
+}
+
+pub fn uncertify_asset(hash: &[u8; 32]) {
+    store::http_tree::remove(&["asset", hex::encode(hash).as_str()]);
+}
+
+// Recertifies the responses serving the asset, called when its encoded variants are changed.
+pub fn recertify_asset(hash: &[u8; 32]) {
+    uncertify_asset(hash);
+    for tid in store::asset_tokens::list(hash) {
+        if let Some(token) = get_token(tid) {
+            certify_token(&token);
+        }
+    }
+}
+
+// Certifies the collection and the 404 fallbacks, called after init and upgrade.
+// The tokens are certified by `certify_tokens` in bounded steps.
+pub fn certify_routes() {
+    certify_collection();
+    // the 404 fallback of the root, and of the routes with the redirects that are certified by `certify_token`
+    let not_found = not_found_hash();
+    store::http_tree::insert(&[], true, expr_hash(CERT_EXPR), &[not_found]);
+    for route in ["token", "metadata"] {
+        store::http_tree::insert(&[route], true, expr_hash(CERT_EXPR), &[not_found]);
+    }
+}
+
+// Certifies the tokens from the token index `next` one token at a time while `has_budget` returns true.
+// The asset contents are not read. Returns the index of the next token to certify, or None when all are certified.
+pub fn certify_tokens(mut next: u32, has_budget: impl Fn() -> bool) -> Option<u32> {
+    while has_budget() {
+        match store::tokens::with(|r| r.get(next as u64)) {
+            Some(token) => certify_token(&token),
+            None => return None,
+        }
+        next += 1;
+    }
+    Some(next)
+}
+
+fn certified_headers(content_type: &str, hash: &[u8; 32]) -> Vec<(String, String)> {
+    vec![
+        ("content-type".to_string(), content_type.to_string()),
+        ("etag".to_string(), format!("\"{}\"", hex::encode(hash))),
+    ]
+}
+
+fn expr_hash(expr: &str) -> [u8; 32] {
+    sha256(expr.as_bytes())
+}
+
+fn redirect_hash(location: &str) -> [u8; 32] {
+    let headers = [("location".to_string(), location.to_string())];
+    response_hash(REDIRECT_CERT_EXPR, 308, &headers, &sha256(b""))
+}
+
+fn not_found_hash() -> [u8; 32] {
+    let res = HttpResponse::text(404, "not found");
+    response_hash(CERT_EXPR, 404, &res.headers, &sha256(&res.body))
+}
+
+// Appends the "ic-certificate" header of the exact request path, see `store::http_tree::witness`.
+fn push_certificate(headers: &mut Vec<(String, String)>, path: &[&str]) {
+    if path.is_empty() || !store::http_tree::contains(path, false) {
+        return;
+    }
+
+    let tree = store::http_tree::witness(path);
+    let mut expr_path = vec!["http_expr"];
+    expr_path.extend(path);
+    expr_path.push("<$>");
+    push_certificate_header(headers, CERT_EXPR, &tree, &expr_path);
+}
+
+// Appends the "ic-certificate" header of the response certified by the most specific wildcard of the path,
+// see `store::http_tree::witness_wildcard`.
+fn push_wildcard_certificate(
+    headers: &mut Vec<(String, String)>,
+    path: &[&str],
+    expr: &str,
+    response_hash: [u8; 32],
+) {
+    let prefix = match (0..=path.len())
+        .rev()
+        .find(|len| store::http_tree::contains(&path[..*len], true))
+    {
+        Some(prefix) => prefix,
+        None => return,
+    };
+
+    let tree = store::http_tree::witness_wildcard(path, prefix, expr_hash(expr), response_hash);
+    let mut expr_path = vec!["http_expr"];
+    expr_path.extend(&path[..prefix]);
+    expr_path.push("<*>");
+    push_certificate_header(headers, expr, &tree, &expr_path);
+}
+
+fn push_certificate_header(
+    headers: &mut Vec<(String, String)>,
+    expr: &str,
+    tree: &HashTree,
+    expr_path: &[&str],
+) {
+    let certificate = match ic_cdk::api::data_certificate() {
+        Some(certificate) => certificate,
+        None => return,
+    };
+    let tree = to_cbor_bytes(tree);
+    headers.push((
+        "ic-certificate".to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+            BASE64.encode(certificate),
+            BASE64.encode(tree),
+            BASE64.encode(to_cbor_bytes(&expr_path)),
+        ),
+    ));
+    headers.push(("ic-certificateexpression".to_string(), expr.to_string()));
+}
+
+// response_hash = sha256(representation_independent_hash(headers) || sha256(body)),
+// the headers include the ":ic-cert-status" pseudo header and the "ic-certificateexpression" header.
+fn response_hash(
+    expr: &str,
+    status_code: u16,
+    headers: &[(String, String)],
+    body_hash: &[u8; 32],
+) -> [u8; 32] {
+    let mut fields: Vec<Vec<u8>> = headers
+        .iter()
+        .map(|(k, v)| [sha256(k.as_bytes()), sha256(v.as_bytes())].concat())
+        .collect();
+    fields.push([sha256(b"ic-certificateexpression"), sha256(expr.as_bytes())].concat());
+    // the status code is hashed in LEB128 encoding
+    fields.push(
+        [
+            sha256(b":ic-cert-status"),
+            sha256(&leb128(status_code as u64)),
+        ]
+        .concat(),
+    );
+    fields.sort();
+
+    let headers_hash = sha256(&fields.concat());
+    sha256(&[headers_hash.as_slice(), body_hash].concat())
+}
+
+fn leb128(mut value: u64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return buf;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+// Returns a 304 response if the "If-None-Match" header matches the etag of the response.
+fn not_modified(
+    req: &HttpRequest,
+    headers: &[(String, String)],
+    path: &[&str],
+) -> Option<HttpResponse> {
+    let etag = header_value(headers, "etag")?;
+    let matched = header_value(&req.headers, "if-none-match").map_or(false, |v| {
+        v.split(',')
+            .any(|t| t.trim().trim_start_matches("W/") == etag)
+    });
+    if !matched {
+        return None;
+    }
+
+    let mut headers = headers.to_vec();
+    push_certificate(&mut headers, path);
+    Some(HttpResponse {
+        status_code: 304,
+        headers,
+        body: ByteBuf::new(),
+        streaming_strategy: None,
+    })
+}
+
+fn redirect_response(location: String, path: &[&str]) -> HttpResponse {
+    let hash = redirect_hash(&location);
+    let mut headers = vec![("location".to_string(), location)];
+    push_wildcard_certificate(&mut headers, path, REDIRECT_CERT_EXPR, hash);
+    HttpResponse {
+        status_code: 308,
+        headers,
+        body: ByteBuf::new(),
+        streaming_strategy: None,
+    }
+}
+
+fn not_found(path: &[&str]) -> HttpResponse {
+    let mut res = HttpResponse::text(404, "not found");
+    push_wildcard_certificate(&mut res.headers, path, CERT_EXPR, not_found_hash());
+    res
+}
+
+fn json_response(req: &HttpRequest, body: String, path: &[&str]) -> HttpResponse {
+    let mut headers = certified_headers(JSON_CONTENT_TYPE, &sha3_256(body.as_bytes()));
+    headers.push((
+        "cache-control".to_string(),
+        "public, max-age=60".to_string(),
+    ));
+    headers.push(("access-control-allow-origin".to_string(), "*".to_string()));
+    if let Some(res) = not_modified(req, &headers, path) {
+        return res;
+    }
+
+    headers.push(("content-length".to_string(), body.len().to_string()));
+    push_certificate(&mut headers, path);
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body: ByteBuf::from(body.into_bytes()),
+        streaming_strategy: None,
+    }
+}
+
+// The external link of the collection JSON, the canister's own domain by default.
+fn assets_origin() -> String {
+    store::collection::with(|c| c.assets_origin.clone())
+        .map(|origin| origin.trim_end_matches('/').to_string())
+        .unwrap_or_else(|| format!("https://{}.icp0.io", ic_cdk::id()))
+}
+
+// Renders the token metadata in the ERC-721 metadata JSON schema, the custom metadata are the attributes.
+fn token_json(token: &store::Token) -> String {
+    let mut properties = Metadata::new();
+    properties.insert(
+        "asset_name".to_string(),
+        Value::Text(token.asset_name.clone()),
+    );
+    properties.insert(
+        "asset_content_type".to_string(),
+        Value::Text(token.asset_content_type.clone()),
+    );
+    properties.insert(
+        "asset_hash".to_string(),
+        Value::Blob(ByteBuf::from(token.asset_hash.as_slice())),
+    );
+    properties.insert("author".to_string(), Value::Text(token.author.to_text()));
+    if let Some(supply_cap) = token.supply_cap {
+        properties.insert("supply_cap".to_string(), Value::Nat(supply_cap.into()));
+    }
+
+    let attributes = token
+        .metadata
+        .iter()
+        .map(|(k, v)| {
+            Value::Map(
+                [
+                    ("trait_type".to_string(), Value::Text(k.clone())),
+                    ("value".to_string(), v.clone()),
+                ]
+                .into_iter()
+                .collect(),
+            )
+        })
+        .collect();
+
+    let mut doc = Metadata::new();
+    doc.insert("token_id".to_string(), Value::Nat(token.id.into()));
+    doc.insert("name".to_string(), Value::Text(token.name.clone()));
+    if let Some(ref description) = token.description {
+        doc.insert("description".to_string(), Value::Text(description.clone()));
+    }
+    // the asset URLs are relative to the canister's own routes, so that they don't change with the `assets_origin`
+    doc.insert(
+        "image".to_string(),
+        Value::Text(token_location(token.id, None)),
+    );
+    if token.assets.contains_key("animation") {
+        doc.insert(
+            "animation_url".to_string(),
+            Value::Text(token_location(token.id, Some("animation"))),
+        );
+    }
+    if !token.assets.is_empty() {
+        let assets = token
+            .assets
+            .keys()
+            .map(|slot| {
+                (
+                    slot.clone(),
+                    Value::Text(token_location(token.id, Some(slot))),
+                )
+            })
+            .collect();
+        properties.insert("assets".to_string(), Value::Map(assets));
+    }
+    doc.insert("attributes".to_string(), Value::Array(attributes));
+    doc.insert("properties".to_string(), Value::Map(properties));
+    to_json(&Value::Map(doc))
+}
+
+fn collection_json() -> String {
+    let origin = assets_origin();
+    let doc = store::collection::with(|c| {
+        let mut doc = Metadata::new();
+        doc.insert("name".to_string(), Value::Text(c.name.clone()));
+        doc.insert("symbol".to_string(), Value::Text(c.symbol.clone()));
+        if let Some(ref description) = c.description {
+            doc.insert("description".to_string(), Value::Text(description.clone()));
+        }
+        if let Some(ref logo) = c.logo {
+            doc.insert("image".to_string(), Value::Text(logo.clone()));
+        }
+        doc.insert("external_link".to_string(), Value::Text(origin));
+        doc.insert(
+            "total_supply".to_string(),
+            Value::Nat(c.circulating_supply().into()),
+        );
+        if let Some(supply_cap) = c.supply_cap {
+            doc.insert("supply_cap".to_string(), Value::Nat(supply_cap.into()));
+        }
+        doc
+    });
+    to_json(&Value::Map(doc))
+}
+
+// Parses a token type id or a SFT id, returns the id and its token type id.
+fn parse_token_id(id: &str) -> Option<(u64, u32)> {
+    let id: u64 = id.parse().ok()?;
+    let tid = if id > u32::MAX as u64 {
+        SftId::from(id).0
+    } else {
+        id as u32
+    };
+    if tid == 0 {
+        return None;
+    }
+    Some((id, tid))
+}
+
+fn get_token(tid: u32) -> Option<store::Token> {
+    store::tokens::with(|r| r.get(SftId(tid, 0).token_index() as u64))
+}
+
+// The path of the asset of the token type, or of its named asset.
+fn token_location(tid: u32, slot: Option<&str>) -> String {
+    match slot {
+        None => format!("/token/{}", tid),
+        Some(slot) => format!("/token/{}/{}", tid, slot),
+    }
+}
+
+fn find_token_asset(tid: u32, slot: Option<&str>) -> Option<Asset> {
+    let token = get_token(tid)?;
+    let (content_type, hash) = token.asset_of(slot)?;
+    let mut path = vec!["token".to_string(), tid.to_string()];
+    path.extend(slot.map(|slot| slot.to_string()));
+    Some(Asset {
+        path,
+        content_type: content_type.to_string(),
+        hash,
+        // the asset can be changed before the token is minted.
+        cache_control: "public, max-age=60",
+    })
+}
+
+fn find_asset(hash: &str) -> Option<Asset> {
+    let mut data = [0u8; 32];
+    hex::decode_to_slice(hash, &mut data).ok()?;
+
+    let path = vec!["asset".to_string(), hex::encode(data)];
+    let content_type = match store::assets::meta(&data) {
+        // only the assets referenced by tokens are served, they are certified
+        Some(meta) if store::http_tree::contains(&["asset", path[1].as_str()], false) => {
+            meta.content_type
+        }
+        Some(_) => return None,
+        // the assets not moved into chunks yet
+        None if store::assets::exists(&data) => "application/octet-stream".to_string(),
+        None => return None,
+    };
+    Some(Asset {
+        path,
+        content_type,
+        hash: data,
+        cache_control: "public, max-age=31536000, immutable",
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range(" bytes=100- ", 1000), Ok(Some((100, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-2000", 1000), Ok(Some((0, 999))));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=0-0", 1), Ok(Some((0, 0))));
+
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), Ok(None));
+        assert_eq!(parse_range("items=0-1", 1000), Ok(None));
+
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=9-1", 1000), Err(()));
+        assert_eq!(parse_range("bytes=-0", 1000), Err(()));
+        assert_eq!(parse_range("bytes=a-1", 1000), Err(()));
+        assert_eq!(parse_range("bytes=1", 1000), Err(()));
+    }
+
+    #[test]
+    fn test_accepted_encoding() {
+        let meta = store::AssetMeta {
+            encodings: [(1, [1u8; 32]), (2, [2u8; 32])].into(),
+            ..Default::default()
+        };
+        let headers = |value: &str| vec![("Accept-Encoding".to_string(), value.to_string())];
+        assert_eq!(accepted_encoding(&[], &meta), None);
+        assert_eq!(
+            accepted_encoding(&headers("gzip, deflate, br"), &meta),
+            Some(("br", [2u8; 32]))
+        );
+        assert_eq!(
+            accepted_encoding(&headers("GZIP"), &meta),
+            Some(("gzip", [1u8; 32]))
+        );
+        assert_eq!(
+            accepted_encoding(&headers("br;q=0, gzip;q=0.5"), &meta),
+            Some(("gzip", [1u8; 32]))
+        );
+        assert_eq!(accepted_encoding(&headers("identity"), &meta), None);
+
+        let meta = store::AssetMeta {
+            encodings: [(1, [1u8; 32])].into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            accepted_encoding(&headers("br, gzip"), &meta),
+            Some(("gzip", [1u8; 32]))
+        );
+        assert_eq!(accepted_encoding(&headers("br"), &meta), None);
+    }
+
+    #[test]
+    fn test_chunk_range() {
+        let size = ASSET_CHUNK_SIZE as u64 * 2 + 1;
+        assert_eq!(chunk_range(0, size), (0, ASSET_CHUNK_SIZE as u64));
+        assert_eq!(chunk_range(2, size), (size - 1, size));
+        assert_eq!(content_range(0, 10, 10), "bytes 0-9/10");
+        assert!(next_token([0u8; 32], None, 0, ASSET_CHUNK_SIZE as u64).is_none());
+        assert_eq!(next_token([0u8; 32], None, 1, size).unwrap().index, 2);
+        assert!(next_token([0u8; 32], None, 2, size).is_none());
+        let token = next_token([0u8; 32], Some("br".to_string()), 0, size).unwrap();
+        assert_eq!(token.content_encoding.as_deref(), Some("br"));
+    }
+
+    #[test]
+    fn test_served_range() {
+        let chunk = ASSET_CHUNK_SIZE as u64;
+        let size = chunk * 2 + 1;
+        // the whole chunk containing the first byte is served
+        assert_eq!(served_range(0, size), (0, 0, chunk));
+        assert_eq!(served_range(10, size), (0, 0, chunk));
+        assert_eq!(served_range(chunk, size), (1, chunk, chunk * 2));
+        assert_eq!(served_range(chunk + 5, size), (1, chunk, chunk * 2));
+        assert_eq!(served_range(size - 1, size), (2, chunk * 2, size));
+        assert_eq!(served_range(0, 100), (0, 0, 100));
+    }
+}
@@ -1,50 +1,240 @@
-use crate::utils::{sha3_256, Challenge};
-use crate::{is_authenticated, is_controller, store, SECOND};
+use crate::utils::{metadata_diff, sha3_256, Challenge};
+use crate::{
+    api_sft_http, ensure_access, ensure_role, is_authenticated, is_controller, store, Fallback,
+    SECOND,
+};
 use candid::{Nat, Principal};
-use ic_sft_types::{ChallengeArg, CreateTokenArg, SftId, UpdateCollectionArg, UpdateTokenArg};
+use ic_sft_types::{
+    nat_to_u64, AssetSlotArg, ChallengeArg, CreateTokenArg, Metadata, Role, SetArchiveSettingsArg,
+    SetPausedArg, SetTokenMinterArg, SftId, Transaction, UpdateCollectionArg, UpdateTokenArg,
+    Value,
+};
 use serde_bytes::ByteBuf;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
-// Set the minters.
+// The maximum number of named assets of a token.
+const MAX_TOKEN_ASSETS: usize = 8;
+
+// Grant the roles to the principal.
 #[ic_cdk::update(guard = "is_controller")]
-pub fn admin_set_minters(args: BTreeSet<Principal>) -> Result<(), String> {
+pub fn admin_grant_roles(principal: Principal, roles: BTreeSet<Role>) -> Result<(), String> {
+    if roles.is_empty() {
+        return Err("no roles provided".to_string());
+    }
+
     let now = ic_cdk::api::time() / SECOND;
-    store::collection::with_mut(|r| {
+    let changed = store::collection::with_mut(|r| {
+        let granted = r.roles.entry(principal).or_default();
+        let before = granted.clone();
+        granted.extend(roles);
+        if *granted == before {
+            return None;
+        }
+        let after = granted.clone();
         r.updated_at = now;
-        r.minters = args;
+        Some((before, after))
     });
+    // only the changes of the roles are logged
+    if let Some((before, after)) = changed {
+        store::audit_logs::append(
+            ic_cdk::caller(),
+            "admin_grant_roles",
+            roles_metadata(&principal, &before),
+            roles_metadata(&principal, &after),
+            now,
+        );
+    }
     Ok(())
 }
 
-// Set the managers.
+// Revoke the roles from the principal.
 #[ic_cdk::update(guard = "is_controller")]
-pub fn admin_set_managers(args: BTreeSet<Principal>) -> Result<(), String> {
+pub fn admin_revoke_roles(principal: Principal, roles: BTreeSet<Role>) -> Result<(), String> {
     let now = ic_cdk::api::time() / SECOND;
-    store::collection::with_mut(|r| {
+    let changed = store::collection::with_mut(|r| {
+        let granted = r.roles.get_mut(&principal)?;
+        let before = granted.clone();
+        granted.retain(|role| !roles.contains(role));
+        if *granted == before {
+            return None;
+        }
+        let after = granted.clone();
+        if after.is_empty() {
+            r.roles.remove(&principal);
+        }
+        r.updated_at = now;
+        Some((before, after))
+    });
+    if let Some((before, after)) = changed {
+        store::audit_logs::append(
+            ic_cdk::caller(),
+            "admin_revoke_roles",
+            roles_metadata(&principal, &before),
+            roles_metadata(&principal, &after),
+            now,
+        );
+    }
+    Ok(())
+}
+
+// Pause or unpause the operations, it can be called by the controllers or the Pauser role.
+#[ic_cdk::update(guard = "is_authenticated")]
+pub fn sft_set_paused(args: SetPausedArg) -> Result<(), String> {
+    let (caller, _) = ensure_access(Role::Pauser, &[Fallback::Controller]);
+
+    let now = ic_cdk::api::time() / SECOND;
+    let (before, after) = store::collection::with_mut(|r| {
+        let before = r.paused.metadata();
+        let flags = [
+            (&mut r.paused.transfers, args.transfers),
+            (&mut r.paused.transfer_from, args.transfer_from),
+            (&mut r.paused.mint, args.mint),
+            (&mut r.paused.approvals, args.approvals),
+            (&mut r.paused.token_creation, args.token_creation),
+        ];
+        for (flag, val) in flags {
+            if let Some(val) = val {
+                *flag = val;
+            }
+        }
+        r.updated_at = now;
+        (before, r.paused.metadata())
+    });
+
+    // the paused flags are a part of the collection metadata, so the changes are logged as 7update blocks too
+    let (prev, next) = metadata_diff(&before, &after);
+    if !prev.is_empty() || !next.is_empty() {
+        store::audit_logs::append(caller, "sft_set_paused", prev, next.clone(), now);
+        let tx_log = Transaction::update(ic_cdk::api::time(), 0, caller, next, None);
+        store::blocks::append(tx_log).unwrap_or_else(|err| ic_cdk::trap(&err));
+    }
+    Ok(())
+}
+
+// Grant the principal to mint the token, with an optional quota and expiry.
+// Setting the grant again replaces the previous one.
+#[ic_cdk::update(guard = "is_controller")]
+pub fn admin_set_token_minter(args: SetTokenMinterArg) -> Result<(), String> {
+    let id = SftId::from(&args.token_id);
+    if store::tokens::with(|r| r.get(id.token_index() as u64)).is_none() {
+        return Err("token not found".to_string());
+    }
+
+    let now = ic_cdk::api::time() / SECOND;
+    // the grant is stored in seconds and 0 means never expires, so a timestamp below one second is rejected too.
+    let expires_at = match args.expires_at {
+        None => 0,
+        Some(ts) if ts / SECOND <= now => {
+            return Err("expires_at should be in the future".to_string());
+        }
+        Some(ts) => ts / SECOND,
+    };
+
+    let grant = store::MintGrant {
+        quota: args.quota,
+        expires_at,
+    };
+    let after = token_minter_metadata(id.0, &args.minter, Some(&grant));
+    let prev = store::token_minters::set(id.0, args.minter, grant);
+    store::audit_logs::append(
+        ic_cdk::caller(),
+        "admin_set_token_minter",
+        token_minter_metadata(id.0, &args.minter, prev.as_ref()),
+        after,
+        now,
+    );
+    Ok(())
+}
+
+// Remove the grant of the principal to mint the token.
+#[ic_cdk::update(guard = "is_controller")]
+pub fn admin_remove_token_minter(token_id: Nat, minter: Principal) -> Result<(), String> {
+    let id = SftId::from(&token_id);
+    let prev = store::token_minters::remove(id.0, minter)
+        .ok_or_else(|| "token minter not found".to_string())?;
+    store::audit_logs::append(
+        ic_cdk::caller(),
+        "admin_remove_token_minter",
+        token_minter_metadata(id.0, &minter, Some(&prev)),
+        token_minter_metadata(id.0, &minter, None),
+        ic_cdk::api::time() / SECOND,
+    );
+    Ok(())
+}
+
+// Set the archiving settings, the changes are recorded in the audit log.
+#[ic_cdk::update(guard = "is_controller")]
+pub fn admin_set_archive_settings(args: SetArchiveSettingsArg) -> Result<(), String> {
+    let now = ic_cdk::api::time() / SECOND;
+    let (before, after) = store::collection::with_mut(|r| {
+        let before = r.settings.archive_metadata();
+        if let Some(val) = args.archive_trigger_threshold {
+            r.settings.archive_trigger_threshold = val;
+        }
+        if let Some(val) = args.max_blocks_per_archive {
+            r.settings.max_blocks_per_archive = val;
+        }
+        if let Some(val) = args.archive_cycles {
+            r.settings.archive_cycles = val;
+        }
         r.updated_at = now;
-        r.managers = args;
+        (before, r.settings.archive_metadata())
     });
+
+    let (prev, next) = metadata_diff(&before, &after);
+    if !prev.is_empty() || !next.is_empty() {
+        store::audit_logs::append(
+            ic_cdk::caller(),
+            "admin_set_archive_settings",
+            prev,
+            next,
+            now,
+        );
+    }
+    Ok(())
+}
+
+// Set the wasm module of the archive canister, it is used to spawn new archives.
+#[ic_cdk::update(guard = "is_controller")]
+pub fn admin_set_archive_wasm(wasm: ByteBuf) -> Result<(), String> {
+    if wasm.is_empty() {
+        return Err("empty wasm module".to_string());
+    }
+
+    let mut after = Metadata::new();
+    after.insert(
+        "wasm_hash".to_string(),
+        Value::Blob(ByteBuf::from(sha3_256(&wasm).to_vec())),
+    );
+    store::archives::set_wasm(wasm.into_vec());
+    store::audit_logs::append(
+        ic_cdk::caller(),
+        "admin_set_archive_wasm",
+        Metadata::new(),
+        after,
+        ic_cdk::api::time() / SECOND,
+    );
     Ok(())
 }
 
 // Update the collection.
 #[ic_cdk::update(guard = "is_authenticated")]
 pub fn sft_update_collection(args: UpdateCollectionArg) -> Result<(), String> {
-    let caller = ic_cdk::caller();
+    let caller = ensure_role(Role::CollectionEditor);
 
     store::collection::with(|c| {
-        if !c.managers.contains(&caller) {
-            ic_cdk::trap("caller is not a manager");
-        }
-
         if let Some(supply_cap) = args.supply_cap {
-            if supply_cap >= c.supply_cap.unwrap_or(0) {
+            if c.supply_cap.map_or(false, |cap| supply_cap > cap) {
                 ic_cdk::trap("supply cap can not be increased");
             }
+            if supply_cap < c.total_minted {
+                ic_cdk::trap("supply cap can not be less than the minted units");
+            }
         }
     });
 
     let now = ic_cdk::api::time() / SECOND;
+    let before = store::collection::with(|c| c.update_metadata());
     store::collection::with_mut(|r| {
         r.updated_at = now;
 
@@ -94,21 +284,27 @@ pub fn sft_update_collection(args: UpdateCollectionArg) -> Result<(), String> {
         if let Some(val) = args.max_revoke_approvals {
             r.settings.max_revoke_approvals = val;
         }
+        if let Some(val) = args.managers_can_burn {
+            r.settings.managers_can_burn = val;
+        }
     });
 
+    let (prev, next) = metadata_diff(&before, &store::collection::with(|c| c.update_metadata()));
+    if !prev.is_empty() || !next.is_empty() {
+        api_sft_http::certify_collection();
+        store::audit_logs::append(caller, "sft_update_collection", prev, next.clone(), now);
+        let tx_log = Transaction::update(ic_cdk::api::time(), 0, caller, next, None);
+        store::blocks::append(tx_log).unwrap_or_else(|err| ic_cdk::trap(&err));
+    }
+
     Ok(())
 }
 
 // Create a challenge for sft_create_token_by_challenge API.
 #[ic_cdk::update(guard = "is_authenticated")]
 pub fn sft_challenge(args: ChallengeArg) -> Result<ByteBuf, String> {
-    let caller = ic_cdk::caller();
+    ensure_role(Role::TokenCreator);
 
-    store::collection::with(|c| {
-        if !c.managers.contains(&caller) {
-            ic_cdk::trap("caller is not a manager");
-        }
-    });
     let ts = ic_cdk::api::time() / SECOND;
     store::keys::with_challenge_secret(|secret| Ok(ByteBuf::from(args.challenge(secret, ts))))
 }
@@ -116,22 +312,14 @@ pub fn sft_challenge(args: ChallengeArg) -> Result<ByteBuf, String> {
 // Create a token.
 #[ic_cdk::update(guard = "is_authenticated")]
 pub fn sft_create_token(args: CreateTokenArg) -> Result<Nat, String> {
-    let caller = ic_cdk::caller();
+    ensure_role(Role::TokenCreator);
 
-    store::collection::with(|c| {
-        if !c.managers.contains(&caller) {
-            ic_cdk::trap("caller is not a manager");
-        }
-
-        if let Some(supply_cap) = c.supply_cap {
-            if c.total_supply >= supply_cap {
-                ic_cdk::trap("supply cap reached");
-            }
-        }
-    });
+    if store::collection::with(|c| c.paused.token_creation) {
+        return Err("token creation is paused".to_string());
+    }
 
     let now = ic_cdk::api::time() / SECOND;
-    let hash = sha3_256(&args.asset_content);
+    let hash = asset_hash_of(&args)?;
     create_token(args, hash, now)
 }
 
@@ -147,17 +335,13 @@ pub fn sft_create_token_by_challenge(args: CreateTokenArg) -> Result<Nat, String
         .as_ref()
         .unwrap_or_else(|| ic_cdk::trap("challenge is required"));
 
-    store::collection::with(|c| {
-        if let Some(supply_cap) = c.supply_cap {
-            if c.total_supply >= supply_cap {
-                ic_cdk::trap("supply cap reached");
-            }
-        }
-    });
+    if store::collection::with(|c| c.paused.token_creation) {
+        return Err("token creation is paused".to_string());
+    }
 
     let now = ic_cdk::api::time() / SECOND;
     let expire_at = now - 60 * 10;
-    let hash = sha3_256(&args.asset_content);
+    let hash = asset_hash_of(&args)?;
     store::keys::with_challenge_secret(|secret| {
         ChallengeArg {
             author: caller,
@@ -172,32 +356,85 @@ pub fn sft_create_token_by_challenge(args: CreateTokenArg) -> Result<Nat, String
 // Update a token before minted.
 #[ic_cdk::update(guard = "is_authenticated")]
 pub fn sft_update_token(args: UpdateTokenArg) -> Result<(), String> {
-    let caller = ic_cdk::caller();
-
     let id = SftId::from(&args.id);
-    let mut token = store::tokens::with(|r| r.get(id.token_index() as u64)).unwrap_or_else(|| {
+    let token = store::tokens::with(|r| r.get(id.token_index() as u64)).unwrap_or_else(|| {
         ic_cdk::trap("token not found");
     });
 
-    store::collection::with(|c| {
-        if !c.managers.contains(&caller) && token.author != caller {
-            ic_cdk::trap("caller is not a manager or author");
-        }
-    });
+    let (caller, _) = ensure_access(Role::TokenCreator, &[Fallback::Author(token.author)]);
 
-    if token.total_supply > 0 {
+    if token.minted > 0 {
         ic_cdk::trap("token has been minted, can not be updated");
     }
+    // the assets released by the update are counted by the index rebuild
+    store::indexes::ensure_built()?;
 
     if let Some(supply_cap) = args.supply_cap {
-        if supply_cap >= token.supply_cap.unwrap_or(0) {
+        if token.supply_cap.map_or(false, |cap| supply_cap > cap) {
             ic_cdk::trap("supply cap can not be increased");
         }
     }
 
+    let before = token.update_metadata();
     let now = ic_cdk::api::time() / SECOND;
-    token.updated_at = now;
+    let token = update_token(token, args, now)?;
+    let (mut prev, meta) = metadata_diff(&before, &token.update_metadata());
+    if !prev.is_empty() || !meta.is_empty() {
+        let mut next = meta.clone();
+        for m in [&mut prev, &mut next] {
+            m.insert("token_id".to_string(), Value::Nat(id.0.into()));
+        }
+        store::audit_logs::append(caller, "sft_update_token", prev, next, now);
+        let tx_log = Transaction::update(
+            ic_cdk::api::time(),
+            SftId(id.0, 0).to_u64(),
+            caller,
+            meta,
+            None,
+        );
+        store::blocks::append(tx_log).unwrap_or_else(|err| ic_cdk::trap(&err));
+    }
+    api_sft_http::certify_token(&token);
+    store::collection::with(|c| ic_cdk::api::set_certified_data(&c.root_hash()));
+
+    Ok(())
+}
+
+// Applies the update to the token and saves it. The assets are validated before anything is changed,
+// and the references of the assets are moved together with the token write, so that a rejected update
+// leaves the token and its assets as they were.
+fn update_token(
+    mut token: store::Token,
+    args: UpdateTokenArg,
+    now_sec: u64,
+) -> Result<store::Token, String> {
+    let prev_assets = token.asset_hashes();
+    let asset_content_type = args
+        .asset_content_type
+        .unwrap_or_else(|| token.asset_content_type.clone());
+    let asset_hash = match args.asset_content {
+        Some(ref asset_content) => Some(sha3_256(asset_content)),
+        None => match args.asset_hash {
+            Some(hash) => {
+                check_committed_asset(&hash)?;
+                Some(hash)
+            }
+            None => None,
+        },
+    };
+    let mut assets = match args.assets {
+        Some(assets) => prepare_assets(&token.assets, assets)?,
+        None => PreparedAssets::default(),
+    };
+    if let (Some(hash), Some(asset_content)) = (asset_hash, args.asset_content) {
+        assets
+            .contents
+            .push((hash, asset_content_type.clone(), asset_content));
+    }
+    store_contents(assets.contents)?;
 
+    token.updated_at = now_sec;
+    token.asset_content_type = asset_content_type;
     if let Some(name) = args.name {
         token.name = name;
     }
@@ -207,20 +444,10 @@ pub fn sft_update_token(args: UpdateTokenArg) -> Result<(), String> {
     if let Some(asset_name) = args.asset_name {
         token.asset_name = asset_name;
     }
-    if let Some(asset_content_type) = args.asset_content_type {
-        token.asset_content_type = asset_content_type;
-    }
-
-    if let Some(asset_content) = args.asset_content {
-        let hash = sha3_256(&asset_content);
-        store::assets::with_mut(|r| {
-            r.remove(&token.asset_hash);
-            r.insert(hash, asset_content.to_vec());
-        });
-        token.asset_hash = hash;
-    }
 
     if let Some(metadata) = args.metadata {
+        store::attributes::remove(token.id, &token.metadata);
+        store::attributes::insert(token.id, &metadata);
         token.metadata = metadata;
     }
 
@@ -229,25 +456,173 @@ pub fn sft_update_token(args: UpdateTokenArg) -> Result<(), String> {
     }
 
     if let Some(author) = args.author {
-        token.author = author;
+        if author != token.author {
+            store::author_tokens::remove(token.author, token.id);
+            store::author_tokens::insert(author, token.id);
+            token.author = author;
+        }
+    }
+
+    // the new assets are referenced before the replaced ones are released,
+    // an asset moved between the main asset and a slot is kept.
+    let mut released = Vec::new();
+    if let Some(hash) = asset_hash {
+        if hash != token.asset_hash {
+            store::assets::add_ref(hash);
+            released.push(token.asset_hash);
+            token.asset_hash = hash;
+        }
+    }
+    for (slot, asset) in assets.slots {
+        store::assets::add_ref(asset.hash);
+        if let Some(prev) = token.assets.insert(slot, asset) {
+            released.push(prev.hash);
+        }
+    }
+    for hash in released {
+        if store::assets::release(&hash) {
+            api_sft_http::uncertify_asset(&hash);
+        }
+    }
+
+    store::tokens::with_mut(|r| r.set(SftId(token.id, 0).token_index() as u64, &token));
+    store::asset_tokens::update(token.id, &prev_assets, &token.asset_hashes());
+    update_content_types(&token);
+    Ok(token)
+}
+
+// Returns the hash of the inline asset content, or the hash of a committed upload.
+fn asset_hash_of(args: &CreateTokenArg) -> Result<[u8; 32], String> {
+    match args.asset_hash {
+        None => Ok(sha3_256(&args.asset_content)),
+        Some(hash) => {
+            if !args.asset_content.is_empty() {
+                return Err("asset_content should be empty when asset_hash is provided".to_string());
+            }
+            Ok(hash)
+        }
+    }
+}
+
+// The validated named assets of a token.
+#[derive(Default)]
+struct PreparedAssets {
+    slots: Vec<(String, store::TokenAsset)>,
+    // the inline contents, they are stored by `store_contents` after the whole call is validated
+    contents: Vec<([u8; 32], String, ByteBuf)>,
+}
+
+// Validates the named assets without storing anything.
+fn prepare_assets(
+    existing: &BTreeMap<String, store::TokenAsset>,
+    args: Vec<AssetSlotArg>,
+) -> Result<PreparedAssets, String> {
+    let mut slots: BTreeSet<&str> = existing.keys().map(|slot| slot.as_str()).collect();
+    for arg in &args {
+        if arg.slot.is_empty()
+            || arg.slot.len() > 32
+            || !arg
+                .slot
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!("invalid asset slot {:?}", arg.slot));
+        }
+        slots.insert(&arg.slot);
+    }
+    if slots.len() > MAX_TOKEN_ASSETS {
+        return Err(format!(
+            "exceeds the maximum number of assets {}",
+            MAX_TOKEN_ASSETS
+        ));
+    }
+
+    let mut res = PreparedAssets {
+        slots: Vec::with_capacity(args.len()),
+        contents: Vec::new(),
+    };
+    for arg in args {
+        let hash = match arg.asset_hash {
+            Some(hash) => {
+                if !arg.asset_content.is_empty() {
+                    return Err(
+                        "asset_content should be empty when asset_hash is provided".to_string()
+                    );
+                }
+                check_committed_asset(&hash)?;
+                hash
+            }
+            None => {
+                if arg.asset_content.is_empty() {
+                    return Err(format!("empty asset content of slot {:?}", arg.slot));
+                }
+                let hash = sha3_256(&arg.asset_content);
+                res.contents
+                    .push((hash, arg.asset_content_type.clone(), arg.asset_content));
+                hash
+            }
+        };
+        res.slots.push((
+            arg.slot,
+            store::TokenAsset {
+                name: arg.asset_name,
+                content_type: arg.asset_content_type,
+                hash,
+            },
+        ));
     }
 
-    store::tokens::with_mut(|r| r.set(id.token_index() as u64, &token));
+    Ok(res)
+}
 
+// Stores the inline asset contents, the assets are orphaned until referenced by a token.
+fn store_contents(contents: Vec<([u8; 32], String, ByteBuf)>) -> Result<(), String> {
+    for (hash, content_type, content) in contents {
+        store::assets::insert(hash, &content_type, &content)?;
+    }
     Ok(())
 }
 
-fn create_token(args: CreateTokenArg, hash: [u8; 32], now_sec: u64) -> Result<Nat, String> {
-    store::assets::with_mut(|r| {
-        if r.contains_key(&hash) {
-            return Err("asset already exists".to_string());
+// Checks that the asset has been committed by sft_commit_upload or is used by other tokens.
+fn check_committed_asset(hash: &[u8; 32]) -> Result<(), String> {
+    if !store::assets::exists(hash) {
+        return Err("asset not found".to_string());
+    }
+    Ok(())
+}
+
+// "/asset/{hash}" serves the content type that the token sets last for the asset,
+// the changed asset is uncertified here and certified again by `certify_token`.
+fn update_content_types(token: &store::Token) {
+    let assets = token
+        .assets
+        .values()
+        .map(|asset| (asset.content_type.as_str(), &asset.hash));
+    for (content_type, hash) in
+        std::iter::once((token.asset_content_type.as_str(), &token.asset_hash)).chain(assets)
+    {
+        if store::assets::set_content_type(hash, content_type) {
+            api_sft_http::uncertify_asset(hash);
         }
+    }
+}
 
-        r.insert(hash, args.asset_content.to_vec());
-        Ok::<(), String>(())
-    })?;
+fn create_token(args: CreateTokenArg, hash: [u8; 32], now_sec: u64) -> Result<Nat, String> {
+    if args.asset_hash.is_some() {
+        check_committed_asset(&hash)?;
+    }
+    let mut assets = match args.assets {
+        Some(assets) => prepare_assets(&BTreeMap::new(), assets)?,
+        None => PreparedAssets::default(),
+    };
+    if args.asset_hash.is_none() {
+        assets
+            .contents
+            .push((hash, args.asset_content_type.clone(), args.asset_content));
+    }
+    store_contents(assets.contents)?;
 
-    let id = store::tokens::with_mut(|r| {
+    let token = store::tokens::with_mut(|r| {
         let id = r.len() as u32 + 1;
         let token = store::Token {
             id,
@@ -260,19 +635,162 @@ fn create_token(args: CreateTokenArg, hash: [u8; 32], now_sec: u64) -> Result<Na
             supply_cap: args.supply_cap,
             author: args.author,
             total_supply: 0,
+            minted: 0,
             created_at: now_sec,
             updated_at: now_sec,
+            assets: assets.slots.into_iter().collect(),
         };
         match r.push(&token) {
             Err(err) => Err(format!("failed to create token: {}", err)),
-            Ok(_) => Ok(Nat::from(id)),
+            Ok(_) => Ok(token),
         }
     })?;
 
+    store::author_tokens::insert(token.author, token.id);
+    store::attributes::insert(token.id, &token.metadata);
+    store::asset_tokens::update(token.id, &BTreeSet::new(), &token.asset_hashes());
+    store::assets::add_ref(hash);
+    for asset in token.assets.values() {
+        store::assets::add_ref(asset.hash);
+    }
+    update_content_types(&token);
+    api_sft_http::certify_token(&token);
     store::collection::with_mut(|r| {
-        r.total_supply += 1;
+        r.token_types += 1;
         r.updated_at = now_sec;
     });
+    api_sft_http::certify_collection();
+    store::collection::with(|c| ic_cdk::api::set_certified_data(&c.root_hash()));
 
-    Ok(id)
+    Ok(Nat::from(token.id))
+}
+
+// Returns the assets that are not referenced by any token.
+#[ic_cdk::query(guard = "is_controller")]
+pub fn admin_orphaned_assets(prev: Option<[u8; 32]>, take: Option<Nat>) -> Vec<ByteBuf> {
+    if let Err(err) = store::indexes::ensure_built() {
+        ic_cdk::trap(&err);
+    }
+
+    let take = store::collection::take_value(take.as_ref().map(nat_to_u64));
+    store::assets::orphans(prev, take as usize)
+        .into_iter()
+        .map(|hash| ByteBuf::from(hash.as_slice()))
+        .collect()
+}
+
+// Removes the orphaned assets, the assets referenced by tokens or by the unexpired uploads are skipped.
+// Returns the number of removed assets.
+#[ic_cdk::update(guard = "is_controller")]
+pub fn admin_remove_orphaned_assets(hashes: Vec<[u8; 32]>) -> Result<u32, String> {
+    store::indexes::ensure_built()?;
+    let mut removed = 0u32;
+    for hash in hashes {
+        if store::assets::refs(&hash) == 0 && store::assets::exists(&hash) {
+            store::assets::remove(&hash);
+            api_sft_http::uncertify_asset(&hash);
+            removed += 1;
+        }
+    }
+    store::collection::with(|c| ic_cdk::api::set_certified_data(&c.root_hash()));
+    Ok(removed)
+}
+
+fn roles_metadata(principal: &Principal, roles: &BTreeSet<Role>) -> Metadata {
+    let mut res = Metadata::new();
+    res.insert("principal".to_string(), Value::Text(principal.to_text()));
+    res.insert(
+        "roles".to_string(),
+        Value::Array(
+            roles
+                .iter()
+                .map(|role| Value::Text(format!("{:?}", role)))
+                .collect(),
+        ),
+    );
+    res
+}
+
+fn token_minter_metadata(
+    tid: u32,
+    minter: &Principal,
+    grant: Option<&store::MintGrant>,
+) -> Metadata {
+    let mut res = grant.map(|g| g.metadata()).unwrap_or_default();
+    res.insert("token_id".to_string(), Value::Nat(tid.into()));
+    res.insert("minter".to_string(), Value::Text(minter.to_text()));
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::test::new_token;
+
+    fn update_arg(asset_content: &[u8], assets: Vec<AssetSlotArg>) -> UpdateTokenArg {
+        UpdateTokenArg {
+            id: Nat::from(1u64),
+            name: None,
+            description: None,
+            asset_name: None,
+            asset_content_type: None,
+            asset_content: Some(ByteBuf::from(asset_content.to_vec())),
+            asset_hash: None,
+            assets: Some(assets),
+            metadata: None,
+            supply_cap: None,
+            author: None,
+        }
+    }
+
+    fn slot_arg(slot: &str, asset_hash: [u8; 32]) -> AssetSlotArg {
+        AssetSlotArg {
+            slot: slot.to_string(),
+            asset_name: "thumbnail.png".to_string(),
+            asset_content_type: "image/png".to_string(),
+            asset_content: ByteBuf::new(),
+            asset_hash: Some(asset_hash),
+        }
+    }
+
+    #[test]
+    fn test_update_token_assets() {
+        let old = b"old asset".to_vec();
+        let old_hash = sha3_256(&old);
+        store::assets::insert(old_hash, "image/svg+xml", &old).unwrap();
+        store::assets::add_ref(old_hash);
+        let token = store::Token {
+            asset_hash: old_hash,
+            ..new_token(1, None)
+        };
+        store::tokens::with_mut(|r| r.push(&token)).unwrap();
+
+        // an invalid slot rejects the new asset content too
+        let new = b"new asset".to_vec();
+        let new_hash = sha3_256(&new);
+        let arg = update_arg(&new, vec![slot_arg("bad slot", old_hash)]);
+        assert!(update_token(token.clone(), arg, 1).is_err());
+        let stored = store::tokens::with(|r| r.get(0)).unwrap();
+        assert_eq!(stored.asset_hash, old_hash);
+        assert!(stored.assets.is_empty());
+        assert_eq!(stored.updated_at, 0);
+        assert!(store::assets::exists(&old_hash));
+        assert_eq!(store::assets::refs(&old_hash), 1);
+        assert!(!store::assets::exists(&new_hash));
+
+        // the replaced main asset is kept as it is moved to a slot
+        let arg = update_arg(&new, vec![slot_arg("thumbnail", old_hash)]);
+        let token = update_token(token, arg, 1).unwrap();
+        let stored = store::tokens::with(|r| r.get(0)).unwrap();
+        assert_eq!(stored.asset_hash, new_hash);
+        assert_eq!(stored.assets["thumbnail"].hash, old_hash);
+        assert_eq!(store::assets::refs(&old_hash), 1);
+        assert_eq!(store::assets::refs(&new_hash), 1);
+
+        // the asset of the replaced slot is released
+        let arg = update_arg(&new, vec![slot_arg("thumbnail", new_hash)]);
+        update_token(token, arg, 2).unwrap();
+        assert!(!store::assets::exists(&old_hash));
+        assert_eq!(store::assets::refs(&new_hash), 2);
+    }
 }
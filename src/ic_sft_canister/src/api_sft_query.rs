@@ -1,8 +1,20 @@
-use crate::store;
-use candid::Nat;
-use ic_sft_types::{nat_to_u64, SftId};
+use crate::{
+    store::{self, AccountKey},
+    SECOND,
+};
+use candid::{Nat, Principal};
+use ic_sft_types::{
+    nat_to_u64, AuditLogEntry, ListTokensFilter, Role, SearchTokensQuery, SftId, TokenInfo,
+    TokenMinter, TokensPage,
+};
+use icrc_ledger_types::icrc1::account::Account;
 
-// Returns a vector of `token_id`s of all semi-fungible tokens in the `token_id` Token, sorted by `token_id`.
+// The maximum number of Tokens scanned by a listing or search query, so that a sparse filter
+// can not exceed the instruction limit. The caller continues from the returned `last_scanned_id`.
+const MAX_SCANNED_TOKENS: u32 = 10_000;
+
+// Returns the `token_id`s of the semi-fungible tokens in the `token_id` Token after `prev`, sorted by `token_id`.
+// Burned tokens are skipped.
 #[ic_cdk::query]
 pub fn sft_tokens_in(token_id: Nat, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
     let take = store::collection::take_value(take.as_ref().map(nat_to_u64));
@@ -11,21 +23,251 @@ pub fn sft_tokens_in(token_id: Nat, prev: Option<Nat>, take: Option<Nat>) -> Vec
         let id = SftId::from(&token_id);
         r.get(&id.0)
             .map(|hs| {
-                let max_sid = hs.total();
-                let start_sid = if let Some(ref prev) = prev {
-                    SftId::from(prev).1
-                } else {
-                    1u32
-                };
-                let mut res: Vec<Nat> = Vec::with_capacity(take as usize);
-                for sid in start_sid..=max_sid {
-                    res.push(Nat::from(SftId(id.0, sid).to_u64()));
-                    if res.len() as u16 >= take {
-                        return res;
-                    }
-                }
-                res
+                let prev_sid = prev.as_ref().map_or(0, |prev| SftId::from(prev).1);
+                hs.iter_from(prev_sid)
+                    .take(take as usize)
+                    .map(|(sid, _)| Nat::from(SftId(id.0, sid).to_u64()))
+                    .collect()
             })
             .unwrap_or_default()
     })
 }
+
+// Returns the `(token_id, owner)` pairs of the semi-fungible tokens in the `token_id` Token after `prev`,
+// sorted by `token_id`. Burned tokens are skipped.
+#[ic_cdk::query]
+pub fn sft_holders_of(token_id: Nat, prev: Option<Nat>, take: Option<Nat>) -> Vec<(Nat, Account)> {
+    let take = store::collection::take_value(take.as_ref().map(nat_to_u64));
+
+    store::holders::with(|r| {
+        let id = SftId::from(&token_id);
+        r.get(&id.0)
+            .map(|hs| {
+                let prev_sid = prev.as_ref().map_or(0, |prev| SftId::from(prev).1);
+                hs.iter_from(prev_sid)
+                    .take(take as usize)
+                    .map(|(sid, holder)| {
+                        (Nat::from(SftId(id.0, sid).to_u64()), Account::from(*holder))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+// Returns the number of distinct accounts holding tokens of the `token_id` Token.
+#[ic_cdk::query]
+pub fn sft_unique_holders(token_id: Nat) -> Nat {
+    store::holders::with(|r| {
+        let id = SftId::from(&token_id);
+        r.get(&id.0).map_or(0, |hs| hs.unique()).into()
+    })
+}
+
+// Returns the number of units the account holds of each `token_id` Token, in the order of `token_ids`.
+#[ic_cdk::query]
+pub fn sft_balances_of(account: Account, token_ids: Vec<Nat>) -> Vec<Nat> {
+    if token_ids.is_empty() {
+        return vec![];
+    }
+
+    let max_query_batch_size = store::collection::with(|c| c.settings.max_query_batch_size);
+    if token_ids.len() > max_query_batch_size as usize {
+        ic_cdk::trap("exceeds max query batch size");
+    }
+
+    store::holder_tokens::with(|r| {
+        let tokens = r.get(&AccountKey::from(&account));
+        token_ids
+            .iter()
+            .map(|token_id| {
+                let id = SftId::from(token_id);
+                tokens
+                    .as_ref()
+                    .map_or(0, |tokens| tokens.balance_of_token(id.0))
+                    .into()
+            })
+            .collect()
+    })
+}
+
+// Returns the Tokens after `prev` that match the filter, sorted by `token_id`.
+// The filter by author is served from the author index, the other filters are checked on each scanned Token.
+#[ic_cdk::query]
+pub fn sft_list_tokens(
+    prev: Option<Nat>,
+    take: Option<Nat>,
+    filter: Option<ListTokensFilter>,
+) -> TokensPage {
+    let take = store::collection::take_value(take.as_ref().map(nat_to_u64));
+    let prev_tid = prev.as_ref().map_or(0, |prev| SftId::from(prev).0);
+    let filter = filter.unwrap_or_default();
+    let matches = |token: &store::Token| {
+        filter
+            .created_after
+            .map_or(true, |ts| token.created_at >= ts)
+            && filter
+                .created_before
+                .map_or(true, |ts| token.created_at < ts)
+            && filter.minted.map_or(true, |m| m == (token.minted > 0))
+    };
+
+    match filter.author {
+        Some(author) => scan_tokens(
+            |f| {
+                ensure_indexed();
+                store::author_tokens::scan(author, prev_tid, f)
+            },
+            take,
+            matches,
+        ),
+        None => scan_tokens(|f| store::tokens::scan(prev_tid, f), take, matches),
+    }
+}
+
+// Returns a vector of `token_id`s of the Tokens created by `author` after `prev`, sorted by `token_id`.
+#[ic_cdk::query]
+pub fn sft_tokens_by_author(author: Principal, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let take = store::collection::take_value(take.as_ref().map(nat_to_u64));
+    let prev_tid = prev.as_ref().map_or(0, |prev| SftId::from(prev).0);
+    ensure_indexed();
+
+    store::author_tokens::list(author, prev_tid, take as usize)
+        .into_iter()
+        .map(|tid| Nat::from(SftId(tid, 0).to_u64()))
+        .collect()
+}
+
+// Returns the Tokens after `prev` that match the query, sorted by `token_id`.
+#[ic_cdk::query]
+pub fn sft_search_tokens(
+    query: SearchTokensQuery,
+    prev: Option<Nat>,
+    take: Option<Nat>,
+) -> TokensPage {
+    let take = store::collection::take_value(take.as_ref().map(nat_to_u64));
+    let prev_tid = prev.as_ref().map_or(0, |prev| SftId::from(prev).0);
+    let name_prefix = query.name_prefix.map(|prefix| prefix.to_lowercase());
+    let attributes: Vec<(String, [u8; 32])> = query
+        .attributes
+        .iter()
+        .map(|(key, value)| {
+            let hash = store::attributes::hash(key, value).unwrap_or_else(|| {
+                ic_cdk::trap("only Text and Nat attributes are searchable");
+            });
+            (key.clone(), hash)
+        })
+        .collect();
+    if name_prefix.is_none() && attributes.is_empty() {
+        ic_cdk::trap("empty search query");
+    }
+
+    let matches = |token: &store::Token| {
+        attributes.iter().all(|(key, hash)| {
+            token
+                .metadata
+                .get(key)
+                .and_then(|value| store::attributes::hash(key, value))
+                == Some(*hash)
+        }) && name_prefix
+            .as_ref()
+            .map_or(true, |prefix| token.name.to_lowercase().starts_with(prefix))
+    };
+
+    match attributes.first() {
+        Some((_, hash)) => scan_tokens(
+            |f| {
+                ensure_indexed();
+                store::attributes::scan(*hash, prev_tid, f)
+            },
+            take,
+            matches,
+        ),
+        None => scan_tokens(|f| store::tokens::scan(prev_tid, f), take, matches),
+    }
+}
+
+// Traps while the indexes are rebuilt after upgrade, for the queries that read them.
+fn ensure_indexed() {
+    if let Err(err) = store::indexes::ensure_built() {
+        ic_cdk::trap(&err);
+    }
+}
+
+// Scans the token type ids provided by `scan` and returns the matched Tokens, the scan stops
+// at `take` matched Tokens or MAX_SCANNED_TOKENS scanned Tokens, whichever comes first.
+fn scan_tokens(
+    scan: impl FnOnce(&mut dyn FnMut(u32) -> bool),
+    take: u16,
+    matches: impl Fn(&store::Token) -> bool,
+) -> TokensPage {
+    let mut tokens: Vec<TokenInfo> = Vec::with_capacity(take as usize);
+    let mut scanned = 0u32;
+    let mut last_scanned: Option<u32> = None;
+    scan(&mut |tid| {
+        scanned += 1;
+        if let Some(token) = store::tokens::with(|r| r.get(SftId(tid, 0).token_index() as u64)) {
+            if matches(&token) {
+                tokens.push(token.to_info());
+            }
+        }
+        if tokens.len() as u16 >= take || scanned >= MAX_SCANNED_TOKENS {
+            last_scanned = Some(tid);
+            return false;
+        }
+        true
+    });
+
+    TokensPage {
+        tokens,
+        last_scanned_id: last_scanned.map(|tid| Nat::from(SftId(tid, 0).to_u64())),
+    }
+}
+
+// Returns the roles granted to the principal.
+#[ic_cdk::query]
+pub fn sft_roles_of(principal: Principal) -> Vec<Role> {
+    store::collection::with(|c| {
+        c.roles
+            .get(&principal)
+            .map(|roles| roles.iter().cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+// Returns the principals granted to mint the `token_id` Token, expired grants are included.
+#[ic_cdk::query]
+pub fn sft_token_minters(token_id: Nat) -> Vec<TokenMinter> {
+    let id = SftId::from(&token_id);
+    store::token_minters::list(id.0)
+        .into_iter()
+        .map(|(minter, grant)| TokenMinter {
+            minter,
+            quota: grant.quota,
+            expires_at: if grant.expires_at > 0 {
+                Some(grant.expires_at * SECOND)
+            } else {
+                None
+            },
+        })
+        .collect()
+}
+
+// Returns the admin audit logs after `prev`, sorted by index.
+// It is public on purpose, like the ledger blocks, so that reviewers can audit the collection without a role.
+// The logs hold the actors, the roles and the settings, which are already public through `sft_roles_of` and 7update blocks.
+#[ic_cdk::query]
+pub fn sft_audit_logs(prev: Option<Nat>, take: Option<Nat>) -> Vec<AuditLogEntry> {
+    let take = store::collection::take_value(take.as_ref().map(nat_to_u64));
+    store::audit_logs::get(prev.as_ref(), take as u64)
+        .into_iter()
+        .map(|(index, log)| AuditLogEntry {
+            index: Nat::from(index),
+            actor: log.actor,
+            operation: log.operation,
+            before: log.before,
+            after: log.after,
+            timestamp: log.timestamp * SECOND,
+        })
+        .collect()
+}
@@ -1,31 +1,78 @@
-use crate::{is_authenticated, store, SECOND};
-use candid::Nat;
-use ic_sft_types::{MintArg, MintError, MintResult, SftId, Transaction};
+use crate::{
+    api_sft_http, ensure_access, is_authenticated,
+    schema::Validate,
+    store::{self, AccountKey},
+    utils::tx_hash,
+    Fallback, SECOND,
+};
+use candid::{Nat, Principal};
+use ic_sft_types::{
+    BurnArg, BurnError, BurnResult, Metadata, MintArg, MintError, MintResult, Role, SftId,
+    Transaction,
+};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 
 // Mint a token.
+// The Minter role is a superuser that mints any token without quota, the legacy minters are migrated to it.
+// The callers without the Minter role can mint the tokens granted to them, within the quota of the grant.
 #[ic_cdk::update(guard = "is_authenticated")]
 pub fn sft_mint(args: MintArg) -> MintResult {
-    let caller = ic_cdk::caller();
-    if !store::collection::with(|c| c.minters.contains(&caller)) {
-        ic_cdk::trap("caller is not a minter");
-    }
+    let id = SftId::from(&args.token_id);
+    let (caller, grant) = ensure_access(Role::Minter, &[Fallback::MintGrant(id.0)]);
 
     if args.holders.is_empty() {
         ic_cdk::trap("no mint holders provided")
     }
 
+    if store::collection::with(|c| c.paused.mint) {
+        return Err(MintError::GenericError {
+            error_code: Nat::from(0u64),
+            message: "mint is paused".to_string(),
+        });
+    }
+
+    // the minted counters are counted by the index rebuild
+    if let Err(err) = store::indexes::ensure_built() {
+        return Err(MintError::GenericError {
+            error_code: Nat::from(0u64),
+            message: err,
+        });
+    }
+
+    if let Some(quota) = grant.as_ref().and_then(|g| g.quota) {
+        if args.holders.len() > quota as usize {
+            return Err(MintError::GenericError {
+                error_code: Nat::from(0u64),
+                message: format!("exceeds the mint quota {}", quota),
+            });
+        }
+    }
+
     let settings = store::collection::with(|c| c.settings.clone());
     if args.holders.len() > settings.max_update_batch_size as usize {
         ic_cdk::trap("exceeds max update batch size");
     }
 
-    let id = SftId::from(&args.token_id);
+    let now = ic_cdk::api::time();
+    args.validate(now, &caller, &settings)?;
+
+    let dedup = args
+        .created_at_time
+        .map(|ts| (tx_hash("7mint", &caller, &args), ts));
+    if let Some(idx) = dedup.and_then(|(h, _)| store::tx_dedup::find(&h, now, &settings)) {
+        return Err(MintError::Duplicate {
+            duplicate_of: Nat::from(idx),
+        });
+    }
+
+    if !store::collection::with(|c| c.can_mint(args.holders.len() as u64)) {
+        return Err(MintError::SupplyCapReached);
+    }
+
     let metadata = store::tokens::with(|r| {
         if let Some(token) = r.get(id.token_index() as u64) {
-            if let Some(supply_cap) = token.supply_cap {
-                if token.total_supply.saturating_add(args.holders.len() as u32) >= supply_cap {
-                    return Err(MintError::SupplyCapReached);
-                }
+            if !token.can_mint(args.holders.len() as u32) {
+                return Err(MintError::SupplyCapReached);
             }
 
             Ok(token.metadata())
@@ -34,49 +81,293 @@ pub fn sft_mint(args: MintArg) -> MintResult {
         }
     })?;
 
-    let now = ic_cdk::api::time();
+    let (added_holders, res) = mint_to(
+        id.0,
+        caller,
+        &args,
+        &metadata,
+        dedup,
+        now,
+        store::blocks::append,
+    );
+    if added_holders > 0 {
+        if grant.is_some() {
+            store::token_minters::consume(id.0, caller, added_holders);
+        }
+        // the circulating supply in "/collection.json" is changed
+        api_sft_http::certify_collection();
+        store::collection::with(|c| ic_cdk::api::set_certified_data(&c.root_hash()));
+    }
+    res
+}
+
+// Appends a 7mint block for each holder with `append` and records the minted units,
+// returns the number of the minted units. The units minted before a failed append are kept,
+// so the transaction is deduplicated with the last appended block and a retry does not mint them again.
+fn mint_to(
+    tid: u32,
+    caller: Principal,
+    args: &MintArg,
+    metadata: &Metadata,
+    dedup: Option<([u8; 32], u64)>,
+    now: u64,
+    mut append: impl FnMut(Transaction) -> Result<u64, String>,
+) -> (u32, MintResult) {
     store::holders::with_mut(|r| {
-        match r.get(&id.0) {
-            None => Err(MintError::NonExistingTokenId),
-            Some(mut holders) => {
-                let mut block_idx = 0u64;
-                let added_holders = args.holders.len() as u32;
-                for holder in args.holders {
+        let mut holders = r.get(&tid).unwrap_or_default();
+        let mut block_idx = 0u64;
+        let mut added_holders = 0u32;
+        let mut res = Ok(());
+        for holder in &args.holders {
+            let holder = AccountKey::from(holder);
+            let sid = holders.total() + 1;
+            let tx_log = Transaction::mint(
+                now,
+                SftId::from(&args.token_id).to_u64(),
+                Some(caller),
+                Account::from(holder),
+                metadata.clone(),
+                args.memo.clone(),
+            )
+            .with_created_at_time(args.created_at_time);
+
+            match append(tx_log) {
+                Ok(idx) => {
                     holders.append(holder);
+                    block_idx = idx;
+                    added_holders += 1;
+                    store::holder_tokens::update_for_mint(holder, tid, sid);
+                }
+                Err(err) => {
+                    // break up when append log failed.
+                    res = Err(MintError::GenericBatchError {
+                        error_code: Nat::from(0u64),
+                        message: err,
+                    });
+                    break;
+                }
+            }
+        }
+
+        if added_holders > 0 {
+            if let Some((hash, ts)) = dedup {
+                store::tx_dedup::insert(hash, ts, block_idx);
+            }
+            r.insert(tid, holders);
+            store::tokens::with_mut(|r| {
+                let idx = SftId(tid, 0).token_index() as u64;
+                if let Some(mut token) = r.get(idx) {
+                    token.total_supply += added_holders;
+                    token.minted += added_holders;
+                    token.updated_at = now / SECOND;
+                    r.set(idx, &token);
+                }
+            });
+            store::collection::with_mut(|c| {
+                c.total_minted += added_holders as u64;
+            });
+        }
 
-                    let tx_log = Transaction::mint(
-                        now,
-                        id.to_u64(),
-                        Some(caller),
-                        holder,
-                        metadata.clone(),
-                        None,
-                    );
-
-                    match store::blocks::append(tx_log) {
-                        Ok(idx) => block_idx = idx,
+        (added_holders, res.map(|_| Nat::from(block_idx)))
+    })
+}
+
+// Burns semi-fungible tokens held by the caller.
+// Burners can burn tokens held by anyone if `managers_can_burn` is enabled in the collection settings,
+// unless they name the subaccount to burn from.
+#[ic_cdk::update(guard = "is_authenticated")]
+pub fn sft_burn(args: Vec<BurnArg>) -> Vec<Option<BurnResult>> {
+    if args.is_empty() {
+        ic_cdk::trap("no burn args provided")
+    }
+
+    let settings = store::collection::with(|c| c.settings.clone());
+    if args.len() > settings.max_update_batch_size as usize {
+        ic_cdk::trap("exceeds max update batch size");
+    }
+    // the burned counters are counted by the index rebuild
+    if let Err(err) = store::indexes::ensure_built() {
+        ic_cdk::trap(&err);
+    }
+
+    let caller = ic_cdk::caller();
+    let is_burner = settings.managers_can_burn
+        && store::collection::with(|c| c.has_role(&caller, Role::Burner));
+    let now = ic_cdk::api::time();
+    store::holders::with_mut(|r| {
+        let mut res: Vec<Option<BurnResult>> = vec![None; args.len()];
+        let mut burned = false;
+        for (index, arg) in args.iter().enumerate() {
+            if let Err(err) = arg.validate(now, &caller, &settings) {
+                res[index] = Some(Err(err));
+                continue;
+            }
+
+            let id = SftId::from(&arg.token_id);
+            match r.get(&id.0) {
+                None => {
+                    res[index] = Some(Err(BurnError::NonExistingTokenId));
+                }
+                Some(mut holders) => {
+                    let from = burn_from(caller, arg.from_subaccount, is_burner);
+                    match holders.burn(from.as_ref(), id.1) {
+                        Ok(holder) => {
+                            let tx_log = Transaction::burn(
+                                now,
+                                id.to_u64(),
+                                Account::from(holder),
+                                None,
+                                arg.memo.clone(),
+                            )
+                            .with_created_at_time(arg.created_at_time);
+
+                            match store::blocks::append(tx_log) {
+                                Ok(idx) => {
+                                    res[index] = Some(Ok(Nat::from(idx)));
+                                    r.insert(id.0, holders);
+                                    store::holder_tokens::update_for_burn(holder, id.0, id.1);
+                                    store::tokens::with_mut(|r| {
+                                        let idx = id.token_index() as u64;
+                                        if let Some(mut token) = r.get(idx) {
+                                            token.total_supply =
+                                                token.total_supply.saturating_sub(1);
+                                            token.updated_at = now / SECOND;
+                                            r.set(idx, &token);
+                                        }
+                                    });
+                                    store::collection::with_mut(|c| {
+                                        c.total_burned += 1;
+                                    });
+                                    burned = true;
+                                }
+                                Err(err) => {
+                                    res[index] = Some(Err(BurnError::GenericBatchError {
+                                        error_code: Nat::from(0u64),
+                                        message: err,
+                                    }));
+                                    // break up when append log failed.
+                                    break;
+                                }
+                            }
+                        }
                         Err(err) => {
-                            // break up when append log failed.
-                            return Err(MintError::GenericBatchError {
-                                error_code: Nat::from(0u64),
-                                message: err,
-                            });
+                            res[index] = Some(Err(err));
                         }
                     }
                 }
-
-                r.insert(id.0, holders);
-                store::tokens::with_mut(|r| {
-                    let idx = id.token_index() as u64;
-                    if let Some(mut token) = r.get(idx) {
-                        token.total_supply += added_holders;
-                        token.updated_at = now / SECOND;
-                        r.set(idx, &token);
-                    }
-                });
-
-                Ok(Nat::from(block_idx))
             }
         }
+
+        if burned {
+            // the circulating supply in "/collection.json" is changed
+            api_sft_http::certify_collection();
+            store::collection::with(|c| ic_cdk::api::set_certified_data(&c.root_hash()));
+        }
+        res
     })
 }
+
+// Returns the account that should hold the burned unit, None lets a Burner burn the unit held by anyone.
+fn burn_from(
+    caller: Principal,
+    from_subaccount: Option<Subaccount>,
+    is_burner: bool,
+) -> Option<AccountKey> {
+    if is_burner && from_subaccount.is_none() {
+        None
+    } else {
+        Some(AccountKey::new(caller, from_subaccount))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::{test::new_token, Holders};
+
+    #[test]
+    fn test_mint_to_partial_failure() {
+        let minter = Principal::self_authenticating(b"minter public key");
+        let holders = ["alice", "bob", "carol"].map(|name| Account {
+            owner: Principal::self_authenticating(name.as_bytes()),
+            subaccount: None,
+        });
+        store::tokens::with_mut(|r| r.push(&new_token(1, None))).unwrap();
+        let now = 1_000 * SECOND;
+        let args = MintArg {
+            token_id: Nat::from(SftId(1, 0).to_u64()),
+            holders: holders.into(),
+            memo: None,
+            created_at_time: Some(now),
+        };
+        let settings = store::Settings {
+            tx_window: 60,
+            permitted_drift: 60,
+            ..Default::default()
+        };
+
+        // the log is full after two blocks
+        let hash = [7u8; 32];
+        let mut appended = 0u64;
+        let (added, res) = mint_to(
+            1,
+            minter,
+            &args,
+            &Metadata::new(),
+            Some((hash, now)),
+            now,
+            |_| {
+                if appended == 2 {
+                    return Err("log is full".to_string());
+                }
+                appended += 1;
+                Ok(appended - 1)
+            },
+        );
+        assert_eq!(added, 2);
+        assert!(matches!(res, Err(MintError::GenericBatchError { .. })));
+        assert_eq!(store::holders::minted(1), 2);
+        assert_eq!(store::tokens::with(|r| r.get(0)).unwrap().minted, 2);
+        store::collection::with(|c| assert_eq!(c.total_minted, 2));
+
+        // the retry is a duplicate of the last appended block
+        assert_eq!(store::tx_dedup::find(&hash, now, &settings), Some(1));
+    }
+
+    #[test]
+    fn test_burn_from() {
+        let burner = Principal::self_authenticating(b"burner public key");
+        let alice = AccountKey::from(Principal::self_authenticating(b"alice public key"));
+        let sub = Some([1u8; 32]);
+        let burner_sub = AccountKey::new(burner, sub);
+        let mut holders = Holders::default();
+        for holder in [alice, burner_sub, alice] {
+            holders.append(holder);
+        }
+
+        // a holder burns from the named account only
+        assert!(matches!(
+            holders.burn(burn_from(burner, None, false).as_ref(), 1),
+            Err(BurnError::Unauthorized)
+        ));
+        assert_eq!(
+            holders
+                .burn(burn_from(burner, sub, false).as_ref(), 2)
+                .unwrap(),
+            burner_sub
+        );
+
+        // a Burner naming the subaccount does not burn the units of others
+        assert!(matches!(
+            holders.burn(burn_from(burner, sub, true).as_ref(), 1),
+            Err(BurnError::Unauthorized)
+        ));
+        assert_eq!(
+            holders
+                .burn(burn_from(burner, None, true).as_ref(), 1)
+                .unwrap(),
+            alice
+        );
+        assert_eq!(holders.get(3), Some(&alice));
+    }
+}
@@ -0,0 +1,79 @@
+use crate::{api_sft_http, ensure_access, ensure_role, is_authenticated, store, Fallback, SECOND};
+use ic_sft_types::{CreateUploadArg, Role, SetAssetEncodingArg, SftId, UploadChunkArg};
+use serde_bytes::ByteBuf;
+
+// Create a staged upload for an asset larger than one ingress message, returns the upload id.
+#[ic_cdk::update(guard = "is_authenticated")]
+pub fn sft_create_upload(args: CreateUploadArg) -> Result<u32, String> {
+    let caller = ensure_role(Role::AssetUploader);
+    // creating an upload releases the expired uploads, their assets may be referenced by tokens not counted yet
+    store::indexes::ensure_built()?;
+
+    if args.asset_size == 0 || args.asset_size > store::uploads::MAX_ASSET_SIZE {
+        return Err(format!(
+            "asset size should be in (0, {}]",
+            store::uploads::MAX_ASSET_SIZE
+        ));
+    }
+
+    let now = ic_cdk::api::time() / SECOND;
+    Ok(store::uploads::create(
+        caller,
+        args.asset_hash,
+        args.asset_size,
+        args.asset_content_type,
+        now,
+    ))
+}
+
+// Upload a chunk of the staged upload. The chunks are uploaded in order of index,
+// each chunk except the last one has 1_800_000 bytes.
+#[ic_cdk::update(guard = "is_authenticated")]
+pub fn sft_upload_chunk(args: UploadChunkArg) -> Result<(), String> {
+    let caller = ensure_role(Role::AssetUploader);
+    if args.content.is_empty() {
+        return Err("empty chunk".to_string());
+    }
+
+    store::uploads::put_chunk(&caller, args.upload_id, args.index, args.content.into_vec())
+}
+
+// Commit the staged upload, returns the asset hash that can be referenced by sft_create_token.
+// The asset is kept until the upload expires even if no token references it.
+#[ic_cdk::update(guard = "is_authenticated")]
+pub fn sft_commit_upload(upload_id: u32) -> Result<ByteBuf, String> {
+    let caller = ensure_role(Role::AssetUploader);
+    let now = ic_cdk::api::time() / SECOND;
+    let hash = store::uploads::commit(&caller, upload_id, now)?;
+    Ok(ByteBuf::from(hash.as_slice()))
+}
+
+// Set a pre-compressed variant of the asset, it is served to the clients that accept the encoding.
+// The encoded content is uploaded as an asset by the staged upload with the hash of the encoded content,
+// and it should decode to the asset content. Only the assets no larger than 8 MiB can have variants.
+// The variants of the assets of minted tokens can only be changed by the collection editors or the controllers.
+#[ic_cdk::update(guard = "is_authenticated")]
+pub fn sft_set_asset_encoding(args: SetAssetEncodingArg) -> Result<(), String> {
+    ensure_role(Role::AssetUploader);
+    store::indexes::ensure_built()?;
+    if is_minted_asset(&args.asset_hash) {
+        ensure_access(Role::CollectionEditor, &[Fallback::Controller]);
+    }
+
+    let code = store::assets::encoding_code(&args.content_encoding)
+        .ok_or_else(|| format!("unsupported content encoding {:?}", args.content_encoding))?;
+    store::assets::set_encoding(args.asset_hash, code, args.encoded_hash)?;
+    api_sft_http::recertify_asset(&args.asset_hash);
+    store::collection::with(|c| ic_cdk::api::set_certified_data(&c.root_hash()));
+    Ok(())
+}
+
+// Returns true if the asset is the main asset or a named asset of a minted token.
+fn is_minted_asset(hash: &[u8; 32]) -> bool {
+    store::tokens::with(|r| {
+        store::asset_tokens::list(hash).into_iter().any(|tid| {
+            r.get(SftId(tid, 0).token_index() as u64)
+                .map_or(false, |token| token.minted > 0)
+        })
+    })
+}
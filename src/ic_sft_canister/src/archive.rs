@@ -0,0 +1,160 @@
+use crate::store;
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+use ic_sft_types::{ArchiveInitArg, Value};
+use ic_stable_structures::Storable;
+use std::cell::Cell;
+
+// The maximum size of blocks sent to an archive canister in one call.
+const MAX_APPEND_BYTES: usize = 1_000_000;
+
+thread_local! {
+    static ARCHIVING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Moves the local blocks to the archive canisters when the number of local blocks
+// reaches the `archive_trigger_threshold`. It is called by the timer periodically.
+pub async fn archive_blocks() {
+    if ARCHIVING.with(|r| r.replace(true)) {
+        return;
+    }
+    scopeguard::defer! {
+        ARCHIVING.with(|r| r.set(false));
+    }
+
+    if let Err(err) = try_archive_blocks().await {
+        ic_cdk::print(format!("failed to archive blocks: {}", err));
+    }
+}
+
+async fn try_archive_blocks() -> Result<(), String> {
+    // continues the compaction started by a previous tick
+    store::blocks::compact();
+
+    let settings = store::collection::with(|c| c.settings.clone());
+    let mut to_archive = store::blocks::total();
+    if settings.archive_trigger_threshold == 0 || to_archive < settings.archive_trigger_threshold {
+        return Ok(());
+    }
+
+    let max_blocks = settings.max_blocks_per_archive.max(1);
+    while to_archive > 0 {
+        let archive = match store::collection::with(|c| c.archives.last().cloned()) {
+            // the code installation failed in a previous tick, retry it with the same canister
+            Some(archive) if archive.pending => install_archive(archive).await?,
+            Some(archive) if archive.end - archive.start < max_blocks => archive,
+            _ => {
+                let start = store::collection::with(|c| c.archived_blocks);
+                let archive = create_archive(start, settings.archive_cycles).await?;
+                install_archive(archive).await?
+            }
+        };
+
+        let capacity = max_blocks - (archive.end - archive.start);
+        let mut size = 0usize;
+        let blocks: Vec<Value> = store::blocks::get_local(0, capacity.min(to_archive))
+            .into_iter()
+            .take_while(|blk| {
+                size += blk.to_bytes().len();
+                size <= MAX_APPEND_BYTES
+            })
+            .map(|blk| blk.into_inner())
+            .collect();
+        if blocks.is_empty() {
+            return Err("block is too large to archive".to_string());
+        }
+
+        let n = blocks.len() as u64;
+        let (res,): (Result<u64, String>,) =
+            ic_cdk::call(archive.canister_id, "append_blocks", (archive.end, blocks))
+                .await
+                .map_err(|(code, msg)| {
+                    format!("failed to call append_blocks, error {:?}: {}", code, msg)
+                })?;
+        res?;
+
+        store::collection::with_mut(|c| {
+            c.archived_blocks += n;
+            if let Some(last) = c.archives.last_mut() {
+                last.end += n;
+            }
+        });
+        store::blocks::compact();
+        to_archive -= n;
+    }
+
+    Ok(())
+}
+
+// Creates an archive canister for the blocks from `start`, it is recorded as pending
+// right after creation so that a failed installation is retried instead of creating another canister.
+async fn create_archive(start: u64, cycles: u64) -> Result<store::Archive, String> {
+    if store::archives::wasm().is_empty() {
+        return Err("archive wasm is not set".to_string());
+    }
+
+    let (res,) = create_canister(
+        CreateCanisterArgument {
+            settings: Some(CanisterSettings {
+                controllers: Some(vec![ic_cdk::id()]),
+                ..Default::default()
+            }),
+        },
+        cycles as u128,
+    )
+    .await
+    .map_err(|(code, msg)| format!("failed to create archive, error {:?}: {}", code, msg))?;
+
+    let archive = store::Archive {
+        canister_id: res.canister_id,
+        start,
+        end: start,
+        pending: true,
+    };
+    store::collection::with_mut(|c| c.archives.push(archive.clone()));
+    Ok(archive)
+}
+
+// Installs the code of the pending archive. The "reinstall" mode also works for an empty canister,
+// and a pending archive holds no blocks, so it is safe to retry.
+async fn install_archive(archive: store::Archive) -> Result<store::Archive, String> {
+    let wasm = store::archives::wasm();
+    if wasm.is_empty() {
+        return Err("archive wasm is not set".to_string());
+    }
+
+    let arg = candid::encode_one(ArchiveInitArg {
+        ledger_id: ic_cdk::id(),
+        start: archive.start,
+    })
+    .map_err(|err| format!("failed to encode archive init arg, error {:?}", err))?;
+    install_code(InstallCodeArgument {
+        mode: CanisterInstallMode::Reinstall,
+        canister_id: archive.canister_id,
+        wasm_module: wasm,
+        arg,
+    })
+    .await
+    .map_err(|(code, msg)| {
+        format!(
+            "failed to install archive {}, error {:?}: {}",
+            archive.canister_id, code, msg
+        )
+    })?;
+
+    store::collection::with_mut(|c| {
+        if let Some(last) = c
+            .archives
+            .iter_mut()
+            .rfind(|a| a.canister_id == archive.canister_id)
+        {
+            last.pending = false;
+        }
+    });
+    Ok(store::Archive {
+        pending: false,
+        ..archive
+    })
+}
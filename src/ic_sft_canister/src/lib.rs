@@ -3,13 +3,20 @@ mod api_icrc3;
 mod api_icrc37;
 mod api_icrc7;
 mod api_init;
+mod api_sft_http;
 mod api_sft_manage;
 mod api_sft_query;
 mod api_sft_update;
+mod api_sft_upload;
+mod archive;
 mod schema;
 mod store;
+mod sweeper;
 mod utils;
 
+use api_sft_http::{
+    HttpRequest, HttpResponse, StreamingCallbackHttpResponse, StreamingCallbackToken,
+};
 use candid::{Nat, Principal};
 use ic_sft_types::*;
 use icrc_ledger_types::icrc1::account::Account;
@@ -39,4 +46,60 @@ fn is_authenticated() -> Result<(), String> {
     }
 }
 
+// The permissions that let a caller without the role pass `ensure_access`.
+enum Fallback {
+    Controller,
+    Author(Principal), // the author of the token
+    MintGrant(u32),    // an active mint grant of the token type
+}
+
+impl Fallback {
+    fn describe(&self) -> &'static str {
+        match self {
+            Fallback::Controller => "a controller",
+            Fallback::Author(_) => "the author",
+            Fallback::MintGrant(_) => "a minter of the token",
+        }
+    }
+}
+
+// Traps if the caller is not granted the role and passes none of the fallbacks.
+// Returns the caller, and the mint grant if the caller passed with it.
+fn ensure_access(role: Role, fallbacks: &[Fallback]) -> (Principal, Option<store::MintGrant>) {
+    let caller = ic_cdk::caller();
+    if store::collection::with(|c| c.has_role(&caller, role)) {
+        return (caller, None);
+    }
+
+    for fallback in fallbacks {
+        match fallback {
+            Fallback::Controller if ic_cdk::api::is_controller(&caller) => {
+                return (caller, None);
+            }
+            Fallback::Author(author) if author == &caller => {
+                return (caller, None);
+            }
+            Fallback::MintGrant(tid) => {
+                let now_sec = ic_cdk::api::time() / SECOND;
+                if let Some(grant) = store::token_minters::get(*tid, caller, now_sec) {
+                    return (caller, Some(grant));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut msg = format!("caller is not granted the {:?} role", role);
+    for fallback in fallbacks {
+        msg.push_str(" or ");
+        msg.push_str(fallback.describe());
+    }
+    ic_cdk::trap(&msg)
+}
+
+// Traps if the caller is not granted the role, returns the caller.
+fn ensure_role(role: Role) -> Principal {
+    ensure_access(role, &[]).0
+}
+
 ic_cdk::export_candid!();
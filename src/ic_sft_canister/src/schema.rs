@@ -1,9 +1,13 @@
-use crate::{store::Settings, ANONYMOUS, SECOND};
+use crate::{
+    store::{AccountKey, Settings},
+    ANONYMOUS, SECOND,
+};
 use candid::{Nat, Principal};
 use ic_sft_types::{
-    ApproveCollectionArg, ApproveCollectionError, ApproveTokenArg, ApproveTokenError,
-    RevokeCollectionApprovalArg, RevokeCollectionApprovalError, RevokeTokenApprovalArg,
-    RevokeTokenApprovalError, TransferArg, TransferError, TransferFromArg, TransferFromError,
+    ApproveCollectionArg, ApproveCollectionError, ApproveTokenArg, ApproveTokenError, BurnArg,
+    BurnError, MintArg, MintError, RevokeCollectionApprovalArg, RevokeCollectionApprovalError,
+    RevokeTokenApprovalArg, RevokeTokenApprovalError, TransferArg, TransferError, TransferFromArg,
+    TransferFromError,
 };
 use std::{convert::From, string::ToString};
 
@@ -25,14 +29,9 @@ impl Validate for TransferArg {
         caller: &Principal,
         settings: &Settings,
     ) -> Result<(), Self::Error> {
-        if self.from_subaccount.is_some() || self.to.subaccount.is_some() {
-            return Err(TransferError::GenericError {
-                error_code: Nat::from(0u64),
-                message: "subaccount is not supported".to_string(),
-            });
-        }
-
-        if self.to.owner == ANONYMOUS || &self.to.owner == caller {
+        if self.to.owner == ANONYMOUS
+            || AccountKey::from(&self.to) == AccountKey::new(*caller, self.from_subaccount)
+        {
             return Err(TransferError::InvalidRecipient);
         }
 
@@ -66,17 +65,9 @@ impl Validate for ApproveTokenArg {
         caller: &Principal,
         settings: &Settings,
     ) -> Result<(), Self::Error> {
-        if self.approval_info.from_subaccount.is_some()
-            || self.approval_info.spender.subaccount.is_some()
-        {
-            return Err(ApproveTokenError::GenericError {
-                error_code: Nat::from(0u64),
-                message: "subaccount is not supported".to_string(),
-            });
-        }
-
         if self.approval_info.spender.owner == ANONYMOUS
-            || &self.approval_info.spender.owner == caller
+            || AccountKey::from(&self.approval_info.spender)
+                == AccountKey::new(*caller, self.approval_info.from_subaccount)
         {
             return Err(ApproveTokenError::InvalidSpender);
         }
@@ -122,17 +113,9 @@ impl Validate for ApproveCollectionArg {
         caller: &Principal,
         settings: &Settings,
     ) -> Result<(), Self::Error> {
-        if self.approval_info.from_subaccount.is_some()
-            || self.approval_info.spender.subaccount.is_some()
-        {
-            return Err(ApproveCollectionError::GenericError {
-                error_code: Nat::from(0u64),
-                message: "subaccount is not supported".to_string(),
-            });
-        }
-
         if self.approval_info.spender.owner == ANONYMOUS
-            || &self.approval_info.spender.owner == caller
+            || AccountKey::from(&self.approval_info.spender)
+                == AccountKey::new(*caller, self.approval_info.from_subaccount)
         {
             return Err(ApproveCollectionError::InvalidSpender);
         }
@@ -178,18 +161,10 @@ impl Validate for RevokeTokenApprovalArg {
         caller: &Principal,
         settings: &Settings,
     ) -> Result<(), Self::Error> {
-        if self.from_subaccount.is_some() || self.spender.map_or(false, |s| s.subaccount.is_some())
-        {
-            return Err(RevokeTokenApprovalError::GenericError {
-                error_code: Nat::from(0u64),
-                message: "subaccount is not supported".to_string(),
-            });
-        }
-
-        if self
-            .spender
-            .map_or(false, |s| s.owner == ANONYMOUS || &s.owner == caller)
-        {
+        let from = AccountKey::new(*caller, self.from_subaccount);
+        if self.spender.map_or(false, |s| {
+            s.owner == ANONYMOUS || AccountKey::from(&s) == from
+        }) {
             return Err(RevokeTokenApprovalError::GenericError {
                 error_code: Nat::from(0u64),
                 message: "invalid spender".to_string(),
@@ -228,18 +203,10 @@ impl Validate for RevokeCollectionApprovalArg {
         caller: &Principal,
         settings: &Settings,
     ) -> Result<(), Self::Error> {
-        if self.from_subaccount.is_some() || self.spender.map_or(false, |s| s.subaccount.is_some())
-        {
-            return Err(RevokeCollectionApprovalError::GenericError {
-                error_code: Nat::from(0u64),
-                message: "subaccount is not supported".to_string(),
-            });
-        }
-
-        if self
-            .spender
-            .map_or(false, |s| s.owner == ANONYMOUS || &s.owner == caller)
-        {
+        let from = AccountKey::new(*caller, self.from_subaccount);
+        if self.spender.map_or(false, |s| {
+            s.owner == ANONYMOUS || AccountKey::from(&s) == from
+        }) {
             return Err(RevokeCollectionApprovalError::GenericError {
                 error_code: Nat::from(0u64),
                 message: "invalid spender".to_string(),
@@ -278,21 +245,13 @@ impl Validate for TransferFromArg {
         caller: &Principal,
         settings: &Settings,
     ) -> Result<(), Self::Error> {
-        if self.spender_subaccount.is_some()
-            || self.from.subaccount.is_some()
-            || self.to.subaccount.is_some()
+        let from = AccountKey::from(&self.from);
+        if self.from.owner == ANONYMOUS || from == AccountKey::new(*caller, self.spender_subaccount)
         {
-            return Err(TransferFromError::GenericError {
-                error_code: Nat::from(0u64),
-                message: "subaccount is not supported".to_string(),
-            });
-        }
-
-        if self.from.owner == ANONYMOUS || &self.from.owner == caller {
             return Err(TransferFromError::Unauthorized);
         }
 
-        if self.to.owner == ANONYMOUS || self.to.owner == self.from.owner {
+        if self.to.owner == ANONYMOUS || AccountKey::from(&self.to) == from {
             return Err(TransferFromError::InvalidRecipient);
         }
 
@@ -317,3 +276,70 @@ impl Validate for TransferFromArg {
         Ok(())
     }
 }
+
+impl Validate for BurnArg {
+    type Error = BurnError;
+    fn validate(
+        &self,
+        now: u64,
+        _caller: &Principal,
+        settings: &Settings,
+    ) -> Result<(), Self::Error> {
+        if let Some(ref memo) = self.memo {
+            if memo.0.len() > settings.max_memo_size as usize {
+                return Err(BurnError::GenericError {
+                    error_code: Nat::from(0u64),
+                    message: "memo size is too large".to_string(),
+                });
+            }
+        }
+        if let Some(created_at_time) = self.created_at_time {
+            if created_at_time > now + settings.permitted_drift * SECOND {
+                return Err(BurnError::CreatedInFuture {
+                    ledger_time: now + settings.permitted_drift,
+                });
+            }
+            if created_at_time < now - (settings.tx_window + settings.permitted_drift) * SECOND {
+                return Err(BurnError::TooOld);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Validate for MintArg {
+    type Error = MintError;
+    fn validate(
+        &self,
+        now: u64,
+        _caller: &Principal,
+        settings: &Settings,
+    ) -> Result<(), Self::Error> {
+        // the tokens held by the anonymous principal are treated as burned
+        if self.holders.iter().any(|holder| holder.owner == ANONYMOUS) {
+            return Err(MintError::GenericError {
+                error_code: Nat::from(0u64),
+                message: "anonymous holder is not allowed".to_string(),
+            });
+        }
+        if let Some(ref memo) = self.memo {
+            if memo.0.len() > settings.max_memo_size as usize {
+                return Err(MintError::GenericError {
+                    error_code: Nat::from(0u64),
+                    message: "memo size is too large".to_string(),
+                });
+            }
+        }
+        if let Some(created_at_time) = self.created_at_time {
+            if created_at_time > now + settings.permitted_drift * SECOND {
+                return Err(MintError::CreatedInFuture {
+                    ledger_time: now + settings.permitted_drift,
+                });
+            }
+            if created_at_time < now - (settings.tx_window + settings.permitted_drift) * SECOND {
+                return Err(MintError::TooOld);
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,27 +1,40 @@
 use candid::{Nat, Principal};
 use ciborium::{from_reader, into_writer};
-use ic_certification::{HashTreeNode, Label};
+use ic_certification::{
+    fork, labeled, leaf, merge_hash_trees, pruned, AsHashTree, HashTree, NestedTree,
+};
 use ic_sft_types::{
-    ApprovalInfo, ApproveTokenError, Metadata, RevokeCollectionApprovalError,
-    RevokeCollectionApprovalResult, RevokeTokenApprovalError, SftId, TransferError,
+    nat_to_u64, ApprovalInfo, ApproveTokenError, BurnError, Metadata,
+    RevokeCollectionApprovalError, RevokeTokenApprovalError, Role, SftId, TokenInfo, TransferError,
     TransferFromError, Value,
 };
-use ic_sft_types::{Block, BlockWithId, GetBlocksRequest, GetBlocksResult, Transaction};
+use ic_sft_types::{
+    ArchivedBlocks, Block, BlockWithId, GetBlocksRequest, GetBlocksResult, QueryArchiveFn,
+    Transaction,
+};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     storable::Bound,
     DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, StableVec, Storable,
 };
-use icrc_ledger_types::{icrc::generic_value::Hash, icrc1::account::Account};
-use serde::{Deserialize, Serialize};
+use icrc_ledger_types::{
+    icrc::generic_value::Hash,
+    icrc1::account::{Account, Subaccount},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_bytes::ByteBuf;
+use sha2::Sha256;
+use sha3::{Digest, Sha3_256};
 use std::{
     borrow::Cow,
     cell::RefCell,
     collections::{BTreeMap, BTreeSet},
 };
 
-use crate::utils::mac_256;
+use crate::{
+    utils::{mac_256, sha3_256},
+    ANONYMOUS, SECOND,
+};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -34,12 +47,41 @@ const HOLDER_APPROVALS_MEMORY_ID: MemoryId = MemoryId::new(5);
 const ASSETS_MEMORY_ID: MemoryId = MemoryId::new(6);
 const BLOCKS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(7);
 const BLOCKS_DATA_MEMORY_ID: MemoryId = MemoryId::new(8);
+const ARCHIVE_WASM_MEMORY_ID: MemoryId = MemoryId::new(9);
+const TX_DEDUP_MEMORY_ID: MemoryId = MemoryId::new(10);
+const TX_DEDUP_QUEUE_MEMORY_ID: MemoryId = MemoryId::new(11);
+const UPLOADS_MEMORY_ID: MemoryId = MemoryId::new(12);
+const UPLOAD_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(13);
+// MemoryId 14 held the encoded variants before they were stored as assets.
+const ASSET_REFS_MEMORY_ID: MemoryId = MemoryId::new(15);
+const AUTHOR_TOKENS_MEMORY_ID: MemoryId = MemoryId::new(16);
+const ATTRIBUTES_MEMORY_ID: MemoryId = MemoryId::new(17);
+const TOKEN_MINTERS_MEMORY_ID: MemoryId = MemoryId::new(18);
+const AUDIT_LOGS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(19);
+const AUDIT_LOGS_DATA_MEMORY_ID: MemoryId = MemoryId::new(20);
+const ASSET_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(21);
+const ASSET_META_MEMORY_ID: MemoryId = MemoryId::new(22);
+const BLOCKS_NEXT_INDEX_MEMORY_ID: MemoryId = MemoryId::new(23);
+const BLOCKS_NEXT_DATA_MEMORY_ID: MemoryId = MemoryId::new(24);
+const ASSET_TOKENS_MEMORY_ID: MemoryId = MemoryId::new(25);
+
+// The memories of the two block logs, the local blocks are rotated between them by `blocks::compact`.
+const BLOCKS_MEMORY_IDS: [(MemoryId, MemoryId); 2] = [
+    (BLOCKS_INDEX_MEMORY_ID, BLOCKS_DATA_MEMORY_ID),
+    (BLOCKS_NEXT_INDEX_MEMORY_ID, BLOCKS_NEXT_DATA_MEMORY_ID),
+];
 
 thread_local! {
     static CHALLENGE_SECRET: RefCell<[u8; 32]> = const { RefCell::new([0; 32]) };
 
+    // the "http_expr" subtree of the certified responses, rebuilt from TOKENS and ASSET_META after upgrade
+    static HTTP_TREE: RefCell<NestedTree<Vec<u8>, Vec<u8>>> = RefCell::new(NestedTree::default());
+
     static COLLECTION_HEAP: RefCell<Collection> = RefCell::new(Collection::default());
 
+    // upload id -> the SHA3-256 and SHA-256 hashers of the chunks received, lost on upgrade
+    static UPLOAD_HASHERS: RefCell<BTreeMap<u32, (Sha3_256, Sha256)>> = RefCell::new(BTreeMap::new());
+
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
 
@@ -68,29 +110,126 @@ thread_local! {
         )
     );
 
-    static HOLDER_TOKENS: RefCell<StableBTreeMap<Principal, HolderTokens, Memory>> = RefCell::new(
+    static HOLDER_TOKENS: RefCell<StableBTreeMap<AccountKey, HolderTokens, Memory>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with_borrow(|m| m.get(HOLDER_TOKENS_MEMORY_ID)),
         )
     );
 
-    static HOLDER_APPROVALS: RefCell<StableBTreeMap<Principal, Approvals, Memory>> = RefCell::new(
+    static HOLDER_APPROVALS: RefCell<StableBTreeMap<AccountKey, Approvals, Memory>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with_borrow(|m| m.get(HOLDER_APPROVALS_MEMORY_ID)),
         )
     );
 
+    // the assets stored as whole contents before chunking, moved to ASSET_CHUNKS by `assets::migrate`
     static ASSETS: RefCell<StableBTreeMap<[u8; 32], Vec<u8>, Memory>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with_borrow(|m| m.get(ASSETS_MEMORY_ID)),
         )
     );
 
-    static BLOCKS: RefCell<StableLog<Block, Memory, Memory>> = RefCell::new(
+    // (asset hash, chunk index) -> chunk of ASSET_CHUNK_SIZE bytes, the last chunk may be shorter
+    static ASSET_CHUNKS: RefCell<StableBTreeMap<([u8; 32], u32), Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(ASSET_CHUNKS_MEMORY_ID)),
+        )
+    );
+
+    // asset hash -> the size and the hashes of the chunked asset
+    static ASSET_META: RefCell<StableBTreeMap<[u8; 32], AssetMeta, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(ASSET_META_MEMORY_ID)),
+        )
+    );
+
+    // asset hash -> the number of tokens referencing the asset, 0 means orphaned
+    static ASSET_REFS: RefCell<StableBTreeMap<[u8; 32], u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(ASSET_REFS_MEMORY_ID)),
+        )
+    );
+
+    // (asset hash, token type id) for the main asset and the named assets of the tokens,
+    // rebuilt from TOKENS after upgrade if empty
+    static ASSET_TOKENS: RefCell<StableBTreeMap<([u8; 32], u32), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(ASSET_TOKENS_MEMORY_ID)),
+        )
+    );
+
+    // the block logs, `Collection::blocks_log` is the active one
+    static BLOCKS: [RefCell<StableLog<Block, Memory, Memory>>; 2] = BLOCKS_MEMORY_IDS.map(|(index, data)| {
+        RefCell::new(
+            StableLog::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(index)),
+                MEMORY_MANAGER.with_borrow(|m| m.get(data)),
+            ).expect("failed to init BLOCKS store")
+        )
+    });
+
+    static ARCHIVE_WASM: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(ARCHIVE_WASM_MEMORY_ID)),
+            Vec::new()
+        ).expect("failed to init ARCHIVE_WASM store")
+    );
+
+    // transaction hash -> (created_at_time, block index)
+    static TX_DEDUP: RefCell<StableBTreeMap<[u8; 32], (u64, u64), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(TX_DEDUP_MEMORY_ID)),
+        )
+    );
+
+    // (created_at_time, transaction hash), for pruning the expired entries of TX_DEDUP
+    static TX_DEDUP_QUEUE: RefCell<StableBTreeMap<(u64, [u8; 32]), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(TX_DEDUP_QUEUE_MEMORY_ID)),
+        )
+    );
+
+    // upload id -> staged upload
+    static UPLOADS: RefCell<StableBTreeMap<u32, Upload, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(UPLOADS_MEMORY_ID)),
+        )
+    );
+
+    // (upload id, chunk index) -> chunk content
+    static UPLOAD_CHUNKS: RefCell<StableBTreeMap<(u32, u32), Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(UPLOAD_CHUNKS_MEMORY_ID)),
+        )
+    );
+
+    // (author, token type id), rebuilt from TOKENS after upgrade if empty
+    static AUTHOR_TOKENS: RefCell<StableBTreeMap<(Principal, u32), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUTHOR_TOKENS_MEMORY_ID)),
+        )
+    );
+
+    // (hash of a Text or Nat metadata attribute, token type id), rebuilt from TOKENS after upgrade if empty
+    static ATTRIBUTES: RefCell<StableBTreeMap<([u8; 32], u32), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(ATTRIBUTES_MEMORY_ID)),
+        )
+    );
+
+    // (token type id, minter) -> the grant to mint the token
+    static TOKEN_MINTERS: RefCell<StableBTreeMap<(u32, Principal), MintGrant, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(TOKEN_MINTERS_MEMORY_ID)),
+        )
+    );
+
+    // the append-only log of the admin operations, separate from the ICRC-3 blocks
+    static AUDIT_LOGS: RefCell<StableLog<AuditLog, Memory, Memory>> = RefCell::new(
         StableLog::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(BLOCKS_INDEX_MEMORY_ID)),
-            MEMORY_MANAGER.with_borrow(|m| m.get(BLOCKS_DATA_MEMORY_ID)),
-        ).expect("failed to init BLOCKS store")
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOGS_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOGS_DATA_MEMORY_ID)),
+        ).expect("failed to init AUDIT_LOGS store")
     );
 }
 
@@ -101,17 +240,70 @@ pub struct Collection {
     pub description: Option<String>,
     pub logo: Option<String>,
     pub assets_origin: Option<String>, // for example, "https://assets.panda.fans"
-    pub total_supply: u64,
-    pub supply_cap: Option<u64>,
+    // the number of token types, stored as "total_supply" before the units were counted separately
+    #[serde(alias = "total_supply")]
+    pub token_types: u64,
+    pub supply_cap: Option<u64>, // the cap of the units minted across all token types
+    #[serde(default)]
+    pub total_minted: u64, // the number of units minted across all token types
+    #[serde(default)]
+    pub total_burned: u64, // the number of units burned across all token types
     pub created_at: u64,
     pub updated_at: u64,
     pub last_block_index: Option<u64>,
     pub last_block_hash: Option<Hash>,
     pub archived_blocks: u64,
-
+    #[serde(default)]
+    pub blocks_offset: u64, // the index of the first block in the active log, the blocks before it are archived
+    #[serde(default)]
+    pub blocks_log: u8, // the active block log, 0 or 1
+    #[serde(default)]
+    pub blocks_compaction: Option<BlocksCompaction>,
+    #[serde(default)]
+    pub archives: Vec<Archive>,
+    #[serde(default)]
+    pub last_upload_id: u32, // upload ids are not reused
+
+    // the legacy role sets, moved to `roles` by `collection::init_roles` on upgrade
+    #[serde(default)]
     pub minters: BTreeSet<Principal>,
+    #[serde(default)]
     pub managers: BTreeSet<Principal>,
     pub settings: Settings,
+    #[serde(default)]
+    pub roles: BTreeMap<Principal, BTreeSet<Role>>,
+    #[serde(default)]
+    pub paused: Paused,
+    #[serde(default)]
+    pub indexes: BTreeSet<Index>, // the indexes built from the tokens, the others are rebuilt after upgrade
+    #[serde(default)]
+    pub index_rebuild: Option<IndexRebuild>,
+}
+
+// The operations halted in an incident.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Paused {
+    pub transfers: bool,
+    pub transfer_from: bool,
+    pub mint: bool,
+    pub approvals: bool,
+    pub token_creation: bool,
+}
+
+impl Paused {
+    pub fn metadata(&self) -> Metadata {
+        let flags = [
+            ("sft:paused_transfers", self.transfers),
+            ("sft:paused_transfer_from", self.transfer_from),
+            ("sft:paused_mint", self.mint),
+            ("sft:paused_approvals", self.approvals),
+            ("sft:paused_token_creation", self.token_creation),
+        ];
+        flags
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), Value::Text(v.to_string())))
+            .collect()
+    }
 }
 
 #[derive(Clone, Default, Deserialize, Serialize)]
@@ -126,6 +318,85 @@ pub struct Settings {
     pub permitted_drift: u64,                       // in seconds
     pub max_approvals_per_token_or_collection: u16, // in seconds
     pub max_revoke_approvals: u16,                  // in seconds
+    // the Burner role can burn the tokens held by anyone, the name is kept from when managers burned them
+    #[serde(default)]
+    pub managers_can_burn: bool,
+    #[serde(default)]
+    pub archive_trigger_threshold: u64, // 0 means archiving is disabled
+    #[serde(default)]
+    pub max_blocks_per_archive: u64,
+    #[serde(default)]
+    pub archive_cycles: u64, // cycles for creating an archive canister
+}
+
+impl Settings {
+    // The archiving settings, they are operational and recorded in the audit log, not in 7update blocks.
+    pub fn archive_metadata(&self) -> Metadata {
+        let settings = [
+            ("archive_trigger_threshold", self.archive_trigger_threshold),
+            ("max_blocks_per_archive", self.max_blocks_per_archive),
+            ("archive_cycles", self.archive_cycles),
+        ];
+        settings
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), Value::Nat(v.into())))
+            .collect()
+    }
+}
+
+// The copy of the blocks not archived from the active log to the other log, see `blocks::compact`.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct BlocksCompaction {
+    pub start: u64, // the index of the first block in the other log
+    pub next: u64,  // the index of the next block to copy
+}
+
+// The indexes derived from the tokens, the indexes missing after upgrade are rebuilt by `indexes::rebuild`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum Index {
+    // The minted and burned counters of the collection and the tokens, counted from the holders
+    // for the collections created before the counters were tracked.
+    Counters,
+    // The reference counts of the assets, for the assets stored before reference counting.
+    // The assets not referenced by tokens are counted when they are moved into chunks, see `assets::migrate`.
+    AssetRefs,
+    // The tokens by the hashes of their assets, for the tokens created before the assets were recertified
+    // through their tokens.
+    AssetTokens,
+    // The tokens by their authors, for the tokens created before `list` and `scan` by author.
+    AuthorTokens,
+    // The Text and Nat attributes of the token metadata, for the tokens created before the attribute queries.
+    Attributes,
+}
+
+impl Index {
+    pub const ALL: [Index; 5] = [
+        Index::Counters,
+        Index::AssetRefs,
+        Index::AssetTokens,
+        Index::AuthorTokens,
+        Index::Attributes,
+    ];
+}
+
+// The indexing of the tokens in [next, end) into the missing indexes, see `indexes::rebuild`.
+// The tokens created after it started are indexed when they are created.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct IndexRebuild {
+    pub indexes: BTreeSet<Index>,
+    pub next: u32, // the index of the next token to index
+    pub end: u32,  // the number of tokens when it started
+}
+
+// An archive canister holds the blocks in [start, end).
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Archive {
+    pub canister_id: Principal,
+    pub start: u64,
+    pub end: u64,
+    // the canister is created but its code is not installed yet
+    #[serde(default)]
+    pub pending: bool,
 }
 
 impl Storable for Collection {
@@ -158,7 +429,7 @@ impl Collection {
         }
         res.insert(
             "icrc7:total_supply".to_string(),
-            Value::Nat(self.total_supply.into()),
+            Value::Nat(self.circulating_supply().into()),
         );
         if let Some(supply_cap) = self.supply_cap {
             res.insert(
@@ -166,9 +437,35 @@ impl Collection {
                 Value::Nat(supply_cap.into()),
             );
         }
+        let counters = [
+            ("sft:token_types", self.token_types),
+            ("sft:total_minted", self.total_minted),
+            ("sft:total_burned", self.total_burned),
+            ("sft:circulating_supply", self.circulating_supply()),
+        ];
+        for (k, v) in counters {
+            res.insert(k.to_string(), Value::Nat(v.into()));
+        }
+        res.append(&mut self.paused.metadata());
         res
     }
 
+    pub fn has_role(&self, principal: &Principal, role: Role) -> bool {
+        self.roles
+            .get(principal)
+            .map_or(false, |roles| roles.contains(&role))
+    }
+
+    pub fn circulating_supply(&self) -> u64 {
+        self.total_minted.saturating_sub(self.total_burned)
+    }
+
+    // The cap limits the units ever minted, burned units are not minted again.
+    pub fn can_mint(&self, units: u64) -> bool {
+        self.supply_cap
+            .map_or(true, |cap| self.total_minted.saturating_add(units) <= cap)
+    }
+
     pub fn icrc37_metadata(&self) -> Metadata {
         let mut res = Metadata::new();
         if self.settings.max_approvals_per_token_or_collection > 0 {
@@ -186,26 +483,71 @@ impl Collection {
         res
     }
 
+    // The metadata recorded in 7update blocks, includes the collection settings.
+    pub fn update_metadata(&self) -> Metadata {
+        let mut res = self.metadata();
+        res.append(&mut self.icrc37_metadata());
+        let settings = [
+            (
+                "icrc7:max_query_batch_size",
+                self.settings.max_query_batch_size as u64,
+            ),
+            (
+                "icrc7:max_update_batch_size",
+                self.settings.max_update_batch_size as u64,
+            ),
+            (
+                "icrc7:default_take_value",
+                self.settings.default_take_value as u64,
+            ),
+            ("icrc7:max_take_value", self.settings.max_take_value as u64),
+            ("icrc7:max_memo_size", self.settings.max_memo_size as u64),
+            ("icrc7:tx_window", self.settings.tx_window),
+            ("icrc7:permitted_drift", self.settings.permitted_drift),
+        ];
+        for (k, v) in settings {
+            res.insert(k.to_string(), Value::Nat(v.into()));
+        }
+        res.insert(
+            "icrc7:atomic_batch_transfers".to_string(),
+            Value::Text(self.settings.atomic_batch_transfers.to_string()),
+        );
+        res.insert(
+            "managers_can_burn".to_string(),
+            Value::Text(self.settings.managers_can_burn.to_string()),
+        );
+        if let Some(ref assets_origin) = self.assets_origin {
+            res.insert(
+                "assets_origin".to_string(),
+                Value::Text(assets_origin.clone()),
+            );
+        }
+        res
+    }
+
     pub fn root_hash(&self) -> [u8; 32] {
         self.hash_tree().digest()
     }
 
-    pub fn hash_tree(&self) -> HashTreeNode {
+    // The certified data tree with the "http_expr" subtree pruned, used by ICRC-3.
+    pub fn hash_tree(&self) -> HashTree {
+        fork(pruned(http_tree::root_hash()), self.icrc3_tree())
+    }
+
+    // The labels in a fork must be sorted: "http_expr" < "last_block_hash" < "last_block_index".
+    fn icrc3_tree(&self) -> HashTree {
         match self.last_block_hash {
             Some(hash) => {
                 let last_block_index = self.last_block_index.unwrap_or(0);
-                HashTreeNode::Fork(Box::new((
-                    HashTreeNode::Labeled(
-                        Label::from("last_block_index"),
-                        Box::new(HashTreeNode::Leaf(last_block_index.to_be_bytes().to_vec())),
+                fork(
+                    labeled("last_block_hash", leaf(hash.as_slice().to_vec())),
+                    labeled(
+                        "last_block_index",
+                        leaf(last_block_index.to_be_bytes().to_vec()),
                     ),
-                    HashTreeNode::Labeled(
-                        Label::from("last_block_hash"),
-                        Box::new(HashTreeNode::Leaf(hash.as_slice().to_vec())),
-                    ),
-                )))
+                )
             }
-            None => HashTreeNode::Empty(),
+            None => ic_certification::empty(),
         }
     }
 }
@@ -221,9 +563,21 @@ pub struct Token {
     pub metadata: Metadata,
     pub author: Principal,
     pub supply_cap: Option<u32>,
-    pub total_supply: u32,
+    pub total_supply: u32, // units in circulation
+    #[serde(default)]
+    pub minted: u32, // units ever minted, including the burned ones
     pub created_at: u64,
     pub updated_at: u64,
+    #[serde(default)]
+    pub assets: BTreeMap<String, TokenAsset>, // slot -> asset, besides the main asset
+}
+
+// A named asset of a token, such as a thumbnail or an animation.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TokenAsset {
+    pub name: String,
+    pub content_type: String,
+    pub hash: [u8; 32],
 }
 
 impl Storable for Token {
@@ -243,6 +597,19 @@ impl Storable for Token {
 impl Token {
     pub fn metadata(&self) -> Metadata {
         let mut res = self.metadata.clone();
+        res.append(&mut self.fields_metadata());
+        res
+    }
+
+    // The cap limits the units ever minted, burned units are not minted again.
+    pub fn can_mint(&self, units: u32) -> bool {
+        self.supply_cap
+            .map_or(true, |cap| self.minted.saturating_add(units) <= cap)
+    }
+
+    // The metadata of the token fields, without the attributes.
+    fn fields_metadata(&self) -> Metadata {
+        let mut res = Metadata::new();
         res.insert("icrc7:name".to_string(), Value::Text(self.name.clone()));
         if let Some(ref description) = self.description {
             res.insert(
@@ -262,15 +629,299 @@ impl Token {
             "asset_hash".to_string(),
             Value::Blob(ByteBuf::from(self.asset_hash.as_slice())),
         );
+        if !self.assets.is_empty() {
+            let assets = self
+                .assets
+                .iter()
+                .map(|(slot, asset)| {
+                    let mut item = Metadata::new();
+                    item.insert("asset_name".to_string(), Value::Text(asset.name.clone()));
+                    item.insert(
+                        "asset_content_type".to_string(),
+                        Value::Text(asset.content_type.clone()),
+                    );
+                    item.insert(
+                        "asset_hash".to_string(),
+                        Value::Blob(ByteBuf::from(asset.hash.as_slice())),
+                    );
+                    (slot.clone(), Value::Map(item))
+                })
+                .collect();
+            res.insert("assets".to_string(), Value::Map(assets));
+        }
+        res
+    }
+
+    // Returns the content type and the hash of the main asset or a named asset.
+    pub fn asset_of(&self, slot: Option<&str>) -> Option<(&str, [u8; 32])> {
+        match slot {
+            None => Some((&self.asset_content_type, self.asset_hash)),
+            Some(slot) => self
+                .assets
+                .get(slot)
+                .map(|asset| (asset.content_type.as_str(), asset.hash)),
+        }
+    }
+
+    // Returns the hashes of the main asset and the named assets.
+    pub fn asset_hashes(&self) -> BTreeSet<[u8; 32]> {
+        std::iter::once(self.asset_hash)
+            .chain(self.assets.values().map(|asset| asset.hash))
+            .collect()
+    }
+
+    pub fn to_info(&self) -> TokenInfo {
+        TokenInfo {
+            id: Nat::from(SftId(self.id, 0).to_u64()),
+            name: self.name.clone(),
+            description: self.description.clone(),
+            asset_name: self.asset_name.clone(),
+            asset_content_type: self.asset_content_type.clone(),
+            asset_hash: self.asset_hash,
+            author: self.author,
+            supply_cap: self.supply_cap,
+            total_supply: self.total_supply,
+            minted: self.minted,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+
+    // The metadata recorded in 7update blocks, includes the fields not in `metadata()`.
+    // The attributes are nested under "metadata", so that a replacement that removes attributes is recorded.
+    pub fn update_metadata(&self) -> Metadata {
+        let mut res = self.fields_metadata();
+        res.insert("metadata".to_string(), Value::Map(self.metadata.clone()));
+        res.insert(
+            "author".to_string(),
+            Value::Blob(ByteBuf::from(self.author.as_slice())),
+        );
+        if let Some(supply_cap) = self.supply_cap {
+            res.insert("supply_cap".to_string(), Value::Nat(supply_cap.into()));
+        }
+        res
+    }
+}
+
+// A grant to mint a token type, given to a principal without the Minter role.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MintGrant {
+    pub quota: Option<u32>, // the remaining units, None means unlimited
+    pub expires_at: u64,    // in seconds, 0 means never expires
+}
+
+impl Storable for MintGrant {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode MintGrant data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode MintGrant data")
+    }
+}
+
+impl MintGrant {
+    pub fn is_active(&self, now_sec: u64) -> bool {
+        self.expires_at == 0 || self.expires_at > now_sec
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        let mut res = Metadata::new();
+        if let Some(quota) = self.quota {
+            res.insert("quota".to_string(), Value::Nat(quota.into()));
+        }
+        if self.expires_at > 0 {
+            res.insert("expires_at".to_string(), Value::Nat(self.expires_at.into()));
+        }
         res
     }
 }
 
+// An admin operation that changed the collection, the tokens or the permissions.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AuditLog {
+    pub actor: Principal,
+    pub operation: String,
+    pub before: Metadata,
+    pub after: Metadata,
+    pub timestamp: u64, // in seconds
+}
+
+impl Storable for AuditLog {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode AuditLog data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode AuditLog data")
+    }
+}
+
+// A staged asset upload, the chunks are kept in UPLOAD_CHUNKS and become the asset chunks by `sft_commit_upload`.
+// A committed upload is kept until it expires, it holds a reference to the asset in the meantime.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Upload {
+    pub owner: Principal,
+    pub asset_hash: [u8; 32],
+    pub asset_size: u64,
+    pub received: u64,   // bytes received
+    pub created_at: u64, // the commit time for a committed upload
+    #[serde(default)]
+    pub content_type: String,
+    #[serde(default)]
+    pub committed: bool,
+    #[serde(default)]
+    pub chunks: Vec<[u8; 32]>, // SHA-256 of each chunk received
+}
+
+impl Storable for Upload {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode Upload data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode Upload data")
+    }
+}
+
+// A chunked asset, the content is stored in ASSET_CHUNKS, or in UPLOAD_CHUNKS under the upload that stored it.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct AssetMeta {
+    pub content_type: String,
+    pub size: u64,
+    pub sha256: [u8; 32], // SHA-256 of the content, the body hash of the certified responses
+    pub chunks: Vec<[u8; 32]>, // SHA-256 of each chunk
+    #[serde(default)]
+    pub encodings: BTreeMap<u8, [u8; 32]>, // content encoding -> the asset of the encoded content
+    #[serde(default)]
+    pub upload_id: Option<u32>, // the committed upload holding the chunks
+}
+
+impl Storable for AssetMeta {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode AssetMeta data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode AssetMeta data")
+    }
+}
+
+// An ICRC-1 account as the key of holdings and approvals, the default subaccount is normalized to None.
+// It is encoded as the owner's bytes followed by the subaccount if any, so the principals stored
+// before subaccounts were supported decode as accounts with the default subaccount.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AccountKey {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl AccountKey {
+    pub fn new(owner: Principal, subaccount: Option<Subaccount>) -> Self {
+        AccountKey {
+            owner,
+            subaccount: subaccount.filter(|sub| sub != &[0u8; 32]),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = self.owner.as_slice().to_vec();
+        if let Some(ref sub) = self.subaccount {
+            buf.extend_from_slice(sub);
+        }
+        buf
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, String> {
+        // a principal is at most 29 bytes and a subaccount is 32 bytes
+        let (owner, sub) = match data.len() {
+            0..=29 => (data, None),
+            n if n >= 32 => {
+                let (owner, sub) = data.split_at(n - 32);
+                let mut subaccount = [0u8; 32];
+                subaccount.copy_from_slice(sub);
+                (owner, Some(subaccount))
+            }
+            n => return Err(format!("invalid account length {}", n)),
+        };
+        let owner = Principal::try_from_slice(owner).map_err(|err| err.to_string())?;
+        Ok(Self::new(owner, sub))
+    }
+}
+
+impl From<Principal> for AccountKey {
+    fn from(owner: Principal) -> Self {
+        AccountKey {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+impl From<&Account> for AccountKey {
+    fn from(account: &Account) -> Self {
+        Self::new(account.owner, account.subaccount)
+    }
+}
+
+impl From<AccountKey> for Account {
+    fn from(key: AccountKey) -> Self {
+        Account {
+            owner: key.owner,
+            subaccount: key.subaccount,
+        }
+    }
+}
+
+impl Serialize for AccountKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.encode())
+    }
+}
+
+impl<'de> Deserialize<'de> for AccountKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = ByteBuf::deserialize(deserializer)?;
+        Self::decode(&data).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Storable for AccountKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 61,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(self.encode())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self::decode(&bytes).expect("failed to decode AccountKey data")
+    }
+}
+
 // spender -> (created_at, expires_at)
 // in seconds since the epoch (1970-01-01), 0 means None
 #[derive(Clone, Default, Deserialize, Serialize)]
-pub struct Approvals(BTreeMap<Principal, (u64, u64)>);
-pub type ApprovalItem<'a> = (&'a Principal, &'a (u64, u64));
+pub struct Approvals(BTreeMap<AccountKey, (u64, u64)>);
+pub type ApprovalItem<'a> = (&'a AccountKey, &'a (u64, u64));
 
 impl Storable for Approvals {
     const BOUND: Bound = Bound::Unbounded;
@@ -287,42 +938,75 @@ impl Storable for Approvals {
 }
 
 impl Approvals {
-    pub fn to_info(item: ApprovalItem) -> ApprovalInfo {
+    // `from` is the account of the approver.
+    pub fn to_info(from: &AccountKey, item: ApprovalItem) -> ApprovalInfo {
         ApprovalInfo {
-            spender: Account {
-                owner: *item.0,
-                subaccount: None,
+            spender: Account::from(*item.0),
+            from_subaccount: from.subaccount,
+            created_at_time: if item.1 .0 > 0 {
+                Some(item.1 .0 * SECOND)
+            } else {
+                None
+            },
+            expires_at: if item.1 .1 > 0 {
+                Some(item.1 .1 * SECOND)
+            } else {
+                None
             },
-            from_subaccount: None,
-            created_at_time: if item.1 .0 > 0 { Some(item.1 .0) } else { None },
-            expires_at: if item.1 .1 > 0 { Some(item.1 .1) } else { None },
             memo: None,
         }
     }
 
+    // 0 means the approval never expires
+    pub fn is_active(expire_at: u64, now_sec: u64) -> bool {
+        expire_at == 0 || expire_at > now_sec
+    }
+
     pub fn total(&self) -> u32 {
         self.0.len() as u32
     }
 
+    pub fn is_approved(&self, spender: &AccountKey, now_sec: u64) -> bool {
+        self.0
+            .get(spender)
+            .map_or(false, |(_, expire_at)| Self::is_active(*expire_at, now_sec))
+    }
+
+    // Removes the expired approvals, returns the number of removed approvals.
+    pub fn prune(&mut self, now_sec: u64) -> usize {
+        let total = self.0.len();
+        self.0
+            .retain(|_, (_, expire_at)| Self::is_active(*expire_at, now_sec));
+        total - self.0.len()
+    }
+
+    // Returns true if the spender can be approved within `max_approvals`, the approval of the spender
+    // can be renewed at the limit. The expired approvals are removed first, they don't count toward the limit.
+    pub fn can_approve(&mut self, spender: &AccountKey, max_approvals: u16, now_sec: u64) -> bool {
+        self.prune(now_sec);
+        self.get(spender).is_some() || self.total() < max_approvals as u32
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = ApprovalItem> {
         self.0.iter()
     }
 
-    pub fn get(&self, spender: &Principal) -> Option<(u64, u64)> {
+    pub fn get(&self, spender: &AccountKey) -> Option<(u64, u64)> {
         self.0.get(spender).cloned()
     }
 
-    pub fn insert(&mut self, spender: Principal, create_at_sec: u64, exp_sec: u64) {
+    pub fn insert(&mut self, spender: AccountKey, create_at_sec: u64, exp_sec: u64) {
         self.0.insert(spender, (create_at_sec, exp_sec));
     }
 
-    pub fn revoke(&mut self, spender: &Principal) -> Option<(u64, u64)> {
+    pub fn revoke(&mut self, spender: &AccountKey) -> Option<(u64, u64)> {
         self.0.remove(spender)
     }
 }
 
-#[derive(Clone, Deserialize, Serialize)]
-pub struct Holders(Vec<Principal>);
+// sid -> holder, sid starts from 1 and the holder of a burned token is ANONYMOUS
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Holders(Vec<AccountKey>);
 
 impl Storable for Holders {
     const BOUND: Bound = Bound::Unbounded;
@@ -343,30 +1027,51 @@ impl Holders {
         self.0.len() as u32
     }
 
-    pub fn get(&self, sid: u32) -> Option<&Principal> {
-        self.0.get(sid as usize)
+    pub fn get(&self, sid: u32) -> Option<&AccountKey> {
+        sid.checked_sub(1)
+            .and_then(|i| self.0.get(i as usize))
+            .filter(|holder| holder.owner != ANONYMOUS)
     }
 
-    pub fn is_holder(&self, sid: u32, account: &Principal) -> bool {
+    // Iterates the holders after the `prev` sid, burned tokens are skipped.
+    pub fn iter_from(&self, prev: u32) -> impl Iterator<Item = (u32, &AccountKey)> {
         self.0
-            .get(sid as usize)
-            .map_or(false, |holder| holder == account)
+            .iter()
+            .enumerate()
+            .skip(prev as usize)
+            .map(|(i, holder)| (i as u32 + 1, holder))
+            .filter(|(_, holder)| holder.owner != ANONYMOUS)
+    }
+
+    // Returns the number of distinct accounts that hold at least one token.
+    pub fn unique(&self) -> usize {
+        self.iter_from(0)
+            .map(|(_, holder)| holder)
+            .collect::<BTreeSet<_>>()
+            .len()
+    }
+
+    pub fn is_holder(&self, sid: u32, account: &AccountKey) -> bool {
+        self.get(sid).map_or(false, |holder| holder == account)
     }
 
-    pub fn append(&mut self, account: Principal) {
+    pub fn append(&mut self, account: AccountKey) {
         self.0.push(account);
     }
 
+    fn get_mut(&mut self, sid: u32) -> Option<&mut AccountKey> {
+        sid.checked_sub(1)
+            .and_then(|i| self.0.get_mut(i as usize))
+            .filter(|holder| holder.owner != ANONYMOUS)
+    }
+
     pub fn transfer_to(
         &mut self,
-        from: &Principal,
-        to: &Principal,
+        from: &AccountKey,
+        to: &AccountKey,
         sid: u32,
     ) -> Result<(), TransferError> {
-        let holder = self
-            .0
-            .get_mut(sid as usize)
-            .ok_or(TransferError::NonExistingTokenId)?;
+        let holder = self.get_mut(sid).ok_or(TransferError::NonExistingTokenId)?;
         if holder != from {
             return Err(TransferError::Unauthorized);
         }
@@ -376,13 +1081,12 @@ impl Holders {
 
     pub fn transfer_from(
         &mut self,
-        from: &Principal,
-        to: &Principal,
+        from: &AccountKey,
+        to: &AccountKey,
         sid: u32,
     ) -> Result<(), TransferFromError> {
         let holder = self
-            .0
-            .get_mut(sid as usize)
+            .get_mut(sid)
             .ok_or(TransferFromError::NonExistingTokenId)?;
         if holder != from {
             return Err(TransferFromError::Unauthorized);
@@ -390,6 +1094,15 @@ impl Holders {
         *holder = *to;
         Ok(())
     }
+
+    // burns the token and returns its last holder, `from` is None if the caller is a manager.
+    pub fn burn(&mut self, from: Option<&AccountKey>, sid: u32) -> Result<AccountKey, BurnError> {
+        let holder = self.get_mut(sid).ok_or(BurnError::NonExistingTokenId)?;
+        if from.map_or(false, |from| holder != from) {
+            return Err(BurnError::Unauthorized);
+        }
+        Ok(std::mem::replace(holder, AccountKey::from(ANONYMOUS)))
+    }
 }
 
 #[derive(Clone, Default, Deserialize, Serialize)]
@@ -414,6 +1127,11 @@ impl HolderTokens {
         self.0.values().map(|records| records.len() as u64).sum()
     }
 
+    // Returns the number of units held of the `tid` Token.
+    pub fn balance_of_token(&self, tid: u32) -> u64 {
+        self.0.get(&tid).map_or(0, |records| records.len() as u64)
+    }
+
     pub fn token_ids(&self) -> Vec<u32> {
         self.0.keys().cloned().collect()
     }
@@ -442,14 +1160,31 @@ impl HolderTokens {
         }
     }
 
-    pub fn insert_approvals(
+    // Removes the expired approvals, returns the number of removed approvals.
+    pub fn prune(&mut self, now_sec: u64) -> usize {
+        let mut removed = 0usize;
+        for records in self.0.values_mut() {
+            for approvals in records.values_mut() {
+                if let Some(items) = approvals {
+                    removed += items.prune(now_sec);
+                    if items.0.is_empty() {
+                        *approvals = None;
+                    }
+                }
+            }
+        }
+        removed
+    }
+
+    pub fn insert_approvals(
         &mut self,
         max_approvals: u16,
         tid: u32,
         sid: u32,
-        spender: Principal,
+        spender: AccountKey,
         create_at_sec: u64,
         exp_sec: u64,
+        now_sec: u64,
     ) -> Result<(), ApproveTokenError> {
         match self.0.get_mut(&tid) {
             None => Err(ApproveTokenError::NonExistingTokenId),
@@ -462,7 +1197,7 @@ impl HolderTokens {
                     Ok(())
                 }
                 Some(Some(approvals)) => {
-                    if approvals.total() >= max_approvals as u32 {
+                    if !approvals.can_approve(&spender, max_approvals, now_sec) {
                         Err(ApproveTokenError::GenericBatchError {
                             error_code: Nat::from(0u64),
                             message: "exceeds the maximum number of approvals".to_string(),
@@ -480,7 +1215,7 @@ impl HolderTokens {
         &mut self,
         tid: u32,
         sid: u32,
-        spender: Option<Principal>,
+        spender: Option<AccountKey>,
     ) -> Result<(), RevokeTokenApprovalError> {
         if let Some(records) = self.0.get_mut(&tid) {
             if let Some(approvals) = records.get_mut(&sid) {
@@ -498,6 +1233,7 @@ impl HolderTokens {
                     },
                     None => {
                         *approvals = None;
+                        return Ok(());
                     }
                 }
             }
@@ -507,6 +1243,41 @@ impl HolderTokens {
     }
 }
 
+// Sweeps the entries of `m` from `start`, rewrites the entries changed by `prune` and
+// removes the entries that become empty. Returns the key to resume from, or None if it reached the end.
+fn sweep_map<V: Storable>(
+    m: &mut StableBTreeMap<AccountKey, V, Memory>,
+    start: Option<AccountKey>,
+    prune: impl Fn(&mut V) -> usize,
+    is_empty: impl Fn(&V) -> bool,
+    has_budget: impl Fn() -> bool,
+) -> Option<AccountKey> {
+    let mut next = None;
+    let mut changed: Vec<(AccountKey, V)> = Vec::new();
+    let range = (
+        start.map_or(std::ops::Bound::Unbounded, std::ops::Bound::Included),
+        std::ops::Bound::Unbounded,
+    );
+    for (key, mut val) in m.range(range) {
+        if !has_budget() {
+            next = Some(key);
+            break;
+        }
+        if prune(&mut val) > 0 {
+            changed.push((key, val));
+        }
+    }
+
+    for (key, val) in changed {
+        if is_empty(&val) {
+            m.remove(&key);
+        } else {
+            m.insert(key, val);
+        }
+    }
+    next
+}
+
 pub mod keys {
     use super::*;
 
@@ -580,6 +1351,24 @@ pub mod collection {
         });
     }
 
+    // Moves the legacy minters and managers to the roles. Managers are granted the roles of what they
+    // could do before, that is all roles except Minter and Pauser.
+    pub fn init_roles() {
+        with_mut(|c| {
+            for minter in std::mem::take(&mut c.minters) {
+                c.roles.entry(minter).or_default().insert(Role::Minter);
+            }
+            for manager in std::mem::take(&mut c.managers) {
+                c.roles.entry(manager).or_default().extend([
+                    Role::CollectionEditor,
+                    Role::TokenCreator,
+                    Role::Burner,
+                    Role::AssetUploader,
+                ]);
+            }
+        });
+    }
+
     pub fn save() {
         COLLECTION_HEAP.with(|h| {
             COLLECTION.with(|r| {
@@ -591,6 +1380,92 @@ pub mod collection {
     }
 }
 
+pub mod http_tree {
+    use super::*;
+
+    // The certified responses of a path, see
+    // https://github.com/dfinity/interface-spec/blob/master/spec/http-gateway-protocol-spec.md#response-verification
+    // segments -> "<$>" ("<*>" for a wildcard) -> expr_hash -> "" (no request certification) -> response_hash -> ""
+    fn expr_path(segments: &[&str], wildcard: bool) -> Vec<Vec<u8>> {
+        let mut path: Vec<Vec<u8>> = segments.iter().map(|s| s.as_bytes().to_vec()).collect();
+        path.push(if wildcard {
+            b"<*>".to_vec()
+        } else {
+            b"<$>".to_vec()
+        });
+        path
+    }
+
+    // Certifies the responses of the path, the responses certified before are replaced.
+    pub fn insert(segments: &[&str], wildcard: bool, expr_hash: [u8; 32], responses: &[[u8; 32]]) {
+        let path = expr_path(segments, wildcard);
+        HTTP_TREE.with(|r| {
+            let mut r = r.borrow_mut();
+            r.delete(&path);
+            for hash in responses {
+                let mut leaf_path = path.clone();
+                leaf_path.extend([expr_hash.to_vec(), Vec::new(), hash.to_vec()]);
+                r.insert(&leaf_path, Vec::new());
+            }
+        });
+    }
+
+    // Certifies one more response of the path, the responses certified before are kept.
+    pub fn insert_response(
+        segments: &[&str],
+        wildcard: bool,
+        expr_hash: [u8; 32],
+        response_hash: [u8; 32],
+    ) {
+        let mut leaf_path = expr_path(segments, wildcard);
+        leaf_path.extend([expr_hash.to_vec(), Vec::new(), response_hash.to_vec()]);
+        HTTP_TREE.with(|r| r.borrow_mut().insert(&leaf_path, Vec::new()));
+    }
+
+    pub fn remove(segments: &[&str]) {
+        HTTP_TREE.with(|r| r.borrow_mut().delete(&expr_path(segments, false)));
+    }
+
+    pub fn contains(segments: &[&str], wildcard: bool) -> bool {
+        HTTP_TREE.with(|r| r.borrow().contains_path(&expr_path(segments, wildcard)))
+    }
+
+    // The digest of the "http_expr" labeled subtree.
+    pub fn root_hash() -> [u8; 32] {
+        HTTP_TREE.with(|r| labeled("http_expr", pruned(r.borrow().root_hash())).digest())
+    }
+
+    // The certified data tree that proves the responses of the exact request path, with the ICRC-3 subtree pruned.
+    pub fn witness(segments: &[&str]) -> HashTree {
+        let tree = HTTP_TREE.with(|r| r.borrow().witness(&expr_path(segments, false)));
+        let icrc3 = collection::with(|c| c.icrc3_tree().digest());
+        fork(labeled("http_expr", tree), pruned(icrc3))
+    }
+
+    // The certified data tree that proves the response of the wildcard of the first `prefix` segments,
+    // the absence of the exact path and the absence of the more specific wildcards.
+    // Only the leaf of the response is revealed, a wildcard can certify many responses.
+    pub fn witness_wildcard(
+        segments: &[&str],
+        prefix: usize,
+        expr_hash: [u8; 32],
+        response_hash: [u8; 32],
+    ) -> HashTree {
+        let tree = HTTP_TREE.with(|r| {
+            let r = r.borrow();
+            let mut leaf_path = expr_path(&segments[..prefix], true);
+            leaf_path.extend([expr_hash.to_vec(), Vec::new(), response_hash.to_vec()]);
+            let mut tree = r.witness(&leaf_path);
+            for len in prefix + 1..=segments.len() {
+                tree = merge_hash_trees(tree, r.witness(&expr_path(&segments[..len], true)));
+            }
+            merge_hash_trees(tree, r.witness(&expr_path(segments, false)))
+        });
+        let icrc3 = collection::with(|c| c.icrc3_tree().digest());
+        fork(labeled("http_expr", tree), pruned(icrc3))
+    }
+}
+
 pub mod tokens {
     use super::*;
 
@@ -601,6 +1476,320 @@ pub mod tokens {
     pub fn with_mut<R>(f: impl FnOnce(&mut StableVec<Token, Memory>) -> R) -> R {
         TOKENS.with(|r| f(&mut r.borrow_mut()))
     }
+
+    // Calls `f` with the token type ids after `prev` in order, until it returns false.
+    pub fn scan(prev: u32, mut f: impl FnMut(u32) -> bool) {
+        let total = TOKENS.with(|r| r.borrow().len()) as u32;
+        for tid in prev.saturating_add(1)..=total {
+            if !f(tid) {
+                return;
+            }
+        }
+    }
+}
+
+pub mod author_tokens {
+    use super::*;
+
+    pub fn insert(author: Principal, tid: u32) {
+        AUTHOR_TOKENS.with(|r| r.borrow_mut().insert((author, tid), ()));
+    }
+
+    pub fn remove(author: Principal, tid: u32) {
+        AUTHOR_TOKENS.with(|r| r.borrow_mut().remove(&(author, tid)));
+    }
+
+    // Returns the token type ids created by the author after `prev`, sorted by id.
+    pub fn list(author: Principal, prev: u32, take: usize) -> Vec<u32> {
+        AUTHOR_TOKENS.with(|r| {
+            r.borrow()
+                .range((author, prev.saturating_add(1))..)
+                .take_while(|((a, _), _)| a == &author)
+                .take(take)
+                .map(|((_, tid), _)| tid)
+                .collect()
+        })
+    }
+
+    // Calls `f` with the token type ids created by the author after `prev` in order, until it returns false.
+    pub fn scan(author: Principal, prev: u32, mut f: impl FnMut(u32) -> bool) {
+        AUTHOR_TOKENS.with(|r| {
+            for ((_, tid), _) in r
+                .borrow()
+                .range((author, prev.saturating_add(1))..)
+                .take_while(|((a, _), _)| a == &author)
+            {
+                if !f(tid) {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+pub mod asset_tokens {
+    use super::*;
+
+    // Moves the token from the `prev` assets to the `next` assets.
+    pub fn update(tid: u32, prev: &BTreeSet<[u8; 32]>, next: &BTreeSet<[u8; 32]>) {
+        ASSET_TOKENS.with(|r| {
+            let mut r = r.borrow_mut();
+            for hash in prev.difference(next) {
+                r.remove(&(*hash, tid));
+            }
+            for hash in next.difference(prev) {
+                r.insert((*hash, tid), ());
+            }
+        });
+    }
+
+    // Returns the token type ids referencing the asset, sorted by id.
+    pub fn list(hash: &[u8; 32]) -> Vec<u32> {
+        ASSET_TOKENS.with(|r| {
+            r.borrow()
+                .range((*hash, 0)..=(*hash, u32::MAX))
+                .map(|((_, tid), _)| tid)
+                .collect()
+        })
+    }
+
+    // Returns the content type that a token referencing the asset sets for it.
+    pub fn content_type(hash: &[u8; 32]) -> Option<String> {
+        let tid = list(hash).into_iter().next()?;
+        let token = tokens::with(|r| r.get(SftId(tid, 0).token_index() as u64))?;
+        if token.asset_hash == *hash {
+            return Some(token.asset_content_type);
+        }
+        token
+            .assets
+            .into_values()
+            .find(|asset| asset.hash == *hash)
+            .map(|asset| asset.content_type)
+    }
+}
+
+pub mod attributes {
+    use super::*;
+
+    // Returns the index key of a metadata attribute, only Text and Nat values are indexed.
+    pub fn hash(key: &str, value: &Value) -> Option<[u8; 32]> {
+        let (tag, value) = match value {
+            Value::Text(text) => (b't', text.as_bytes().to_vec()),
+            Value::Nat(nat) => (b'n', nat.0.to_bytes_be()),
+            _ => return None,
+        };
+        let mut data = Vec::with_capacity(key.len() + value.len() + 2);
+        data.extend_from_slice(key.as_bytes());
+        data.push(0);
+        data.push(tag);
+        data.extend_from_slice(&value);
+        Some(sha3_256(&data))
+    }
+
+    pub fn insert(tid: u32, metadata: &Metadata) {
+        ATTRIBUTES.with(|r| {
+            let mut r = r.borrow_mut();
+            for (key, value) in metadata {
+                if let Some(hash) = hash(key, value) {
+                    r.insert((hash, tid), ());
+                }
+            }
+        });
+    }
+
+    pub fn remove(tid: u32, metadata: &Metadata) {
+        ATTRIBUTES.with(|r| {
+            let mut r = r.borrow_mut();
+            for (key, value) in metadata {
+                if let Some(hash) = hash(key, value) {
+                    r.remove(&(hash, tid));
+                }
+            }
+        });
+    }
+
+    // Calls `f` with the token type ids having the attribute after `prev` in order, until `f` returns false.
+    pub fn scan(hash: [u8; 32], prev: u32, mut f: impl FnMut(u32) -> bool) {
+        ATTRIBUTES.with(|r| {
+            for ((_, tid), _) in r
+                .borrow()
+                .range((hash, prev.saturating_add(1))..)
+                .take_while(|((h, _), _)| h == &hash)
+            {
+                if !f(tid) {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+pub mod indexes {
+    use super::*;
+
+    // Starts rebuilding the indexes that are not built, called after upgrade. A rebuild in progress is resumed.
+    pub fn start() {
+        collection::with_mut(|c| {
+            if c.index_rebuild.is_some() {
+                return;
+            }
+
+            let indexes: BTreeSet<Index> = Index::ALL
+                .into_iter()
+                .filter(|index| !c.indexes.contains(index))
+                .collect();
+            if indexes.contains(&Index::Counters) {
+                c.total_minted = 0;
+                c.total_burned = 0;
+            }
+            if !indexes.is_empty() {
+                c.index_rebuild = Some(IndexRebuild {
+                    indexes,
+                    next: 0,
+                    end: tokens::with(|r| r.len()) as u32,
+                });
+            }
+        });
+    }
+
+    // Returns an error while the indexes are rebuilt, for the operations that read or change them.
+    pub fn ensure_built() -> Result<(), String> {
+        if collection::with(|c| c.index_rebuild.is_some()) {
+            return Err("the indexes are being rebuilt, try again later".to_string());
+        }
+        Ok(())
+    }
+
+    // Indexes the tokens into the rebuilt indexes one token at a time while `has_budget` returns true.
+    // Returns true when all the tokens are indexed.
+    pub fn rebuild(has_budget: impl Fn() -> bool) -> bool {
+        let mut task = match collection::with(|c| c.index_rebuild.clone()) {
+            Some(task) => task,
+            None => return true,
+        };
+
+        while task.next < task.end && has_budget() {
+            let mut token = tokens::with(|r| r.get(task.next as u64)).expect("token not found");
+            for index in &task.indexes {
+                match index {
+                    Index::Counters => {
+                        token.minted = holders::minted(token.id);
+                        if token.minted > 0 {
+                            tokens::with_mut(|r| r.set(task.next as u64, &token));
+                        }
+                        collection::with_mut(|c| {
+                            c.total_minted += token.minted as u64;
+                            c.total_burned +=
+                                token.minted.saturating_sub(token.total_supply) as u64;
+                        });
+                    }
+                    Index::AssetRefs => {
+                        assets::add_ref(token.asset_hash);
+                        for asset in token.assets.values() {
+                            assets::add_ref(asset.hash);
+                        }
+                    }
+                    Index::AssetTokens => {
+                        asset_tokens::update(token.id, &BTreeSet::new(), &token.asset_hashes())
+                    }
+                    Index::AuthorTokens => author_tokens::insert(token.author, token.id),
+                    Index::Attributes => attributes::insert(token.id, &token.metadata),
+                }
+            }
+            task.next += 1;
+        }
+
+        collection::with_mut(|c| {
+            if task.next < task.end {
+                c.index_rebuild = Some(task);
+                return false;
+            }
+            c.indexes.extend(task.indexes);
+            c.index_rebuild = None;
+            true
+        })
+    }
+}
+
+pub mod token_minters {
+    use super::*;
+
+    // Returns the grant of the minter if it is not expired.
+    pub fn get(tid: u32, minter: Principal, now_sec: u64) -> Option<MintGrant> {
+        TOKEN_MINTERS
+            .with(|r| r.borrow().get(&(tid, minter)))
+            .filter(|grant| grant.is_active(now_sec))
+    }
+
+    pub fn set(tid: u32, minter: Principal, grant: MintGrant) -> Option<MintGrant> {
+        TOKEN_MINTERS.with(|r| r.borrow_mut().insert((tid, minter), grant))
+    }
+
+    pub fn remove(tid: u32, minter: Principal) -> Option<MintGrant> {
+        TOKEN_MINTERS.with(|r| r.borrow_mut().remove(&(tid, minter)))
+    }
+
+    // Deducts the minted units from the quota of the grant.
+    pub fn consume(tid: u32, minter: Principal, units: u32) {
+        TOKEN_MINTERS.with(|r| {
+            let mut r = r.borrow_mut();
+            if let Some(mut grant) = r.get(&(tid, minter)) {
+                if let Some(quota) = grant.quota {
+                    grant.quota = Some(quota.saturating_sub(units));
+                    r.insert((tid, minter), grant);
+                }
+            }
+        });
+    }
+
+    pub fn list(tid: u32) -> Vec<(Principal, MintGrant)> {
+        TOKEN_MINTERS.with(|r| {
+            r.borrow()
+                .range((tid, Principal::management_canister())..)
+                .take_while(|((t, _), _)| *t == tid)
+                .map(|((_, minter), grant)| (minter, grant))
+                .collect()
+        })
+    }
+}
+
+pub mod audit_logs {
+    use super::*;
+
+    pub fn append(
+        actor: Principal,
+        operation: &str,
+        before: Metadata,
+        after: Metadata,
+        now_sec: u64,
+    ) -> u64 {
+        AUDIT_LOGS.with(|r| {
+            r.borrow_mut()
+                .append(&AuditLog {
+                    actor,
+                    operation: operation.to_string(),
+                    before,
+                    after,
+                    timestamp: now_sec,
+                })
+                .expect("failed to append AuditLog")
+        })
+    }
+
+    // Returns the logs after `prev`, the cursor saturates so that a `prev` beyond u64 ends the listing.
+    pub fn get(prev: Option<&Nat>, take: u64) -> Vec<(u64, AuditLog)> {
+        let start = match prev {
+            None => 0,
+            Some(prev) if *prev >= Nat::from(u64::MAX) => u64::MAX,
+            Some(prev) => nat_to_u64(prev) + 1,
+        };
+        AUDIT_LOGS.with(|r| {
+            let r = r.borrow();
+            (start..r.len().min(start.saturating_add(take)))
+                .filter_map(|i| r.get(i).map(|log| (i, log)))
+                .collect()
+        })
+    }
 }
 
 pub mod holders {
@@ -613,14 +1802,19 @@ pub mod holders {
     pub fn with_mut<R>(f: impl FnOnce(&mut StableBTreeMap<u32, Holders, Memory>) -> R) -> R {
         HOLDERS.with(|r| f(&mut r.borrow_mut()))
     }
+
+    // The number of units ever minted for the token, including the burned ones.
+    pub fn minted(tid: u32) -> u32 {
+        HOLDERS.with(|r| r.borrow().get(&tid).map_or(0, |h| h.total()))
+    }
 }
 
 pub mod holder_tokens {
     use super::*;
 
     pub fn is_approved(
-        from: &Principal,
-        spender: &Principal,
+        from: &AccountKey,
+        spender: &AccountKey,
         tid: u32,
         sid: u32,
         now_sec: u64,
@@ -629,10 +1823,7 @@ pub mod holder_tokens {
             if let Some(tokens) = r.get(from) {
                 if let Some(records) = tokens.0.get(&tid) {
                     if let Some(Some(approvals)) = records.get(&sid) {
-                        return approvals
-                            .0
-                            .get(spender)
-                            .map_or(false, |(_, expire_at)| expire_at > &now_sec);
+                        return approvals.is_approved(spender, now_sec);
                     }
                 }
             }
@@ -640,52 +1831,26 @@ pub mod holder_tokens {
         })
     }
 
-    pub fn spenders_is_approved(
-        from: &Principal,
-        args: &[(SftId, &Principal)],
-        now_sec: u64,
-    ) -> Vec<bool> {
-        with(|r| {
-            let mut res = vec![false; args.len()];
-            if let Some(tokens) = r.get(from) {
-                for (i, (id, spender)) in args.iter().enumerate() {
-                    if let Some(records) = tokens.0.get(&id.0) {
-                        if let Some(Some(approvals)) = records.get(&id.1) {
-                            res[i] = approvals
-                                .0
-                                .get(spender)
-                                .map_or(false, |(_, expire_at)| expire_at > &now_sec);
-                        }
-                    }
-                }
-            }
-            res
-        })
-    }
-
     // used by atomic_batch_transfers checking
     pub fn all_is_approved<'a>(
-        spender: &Principal,
-        args: &'a [&(SftId, &Principal)],
+        spender: &AccountKey,
+        args: &'a [&(SftId, AccountKey)],
         now_sec: u64,
-    ) -> Result<(), &'a Principal> {
+    ) -> Result<(), &'a AccountKey> {
         with(|r| {
             for arg in args.iter() {
-                match r.get(arg.1) {
-                    None => return Err(arg.1),
+                match r.get(&arg.1) {
+                    None => return Err(&arg.1),
                     Some(tokens) => match tokens.0.get(&arg.0 .0) {
-                        None => return Err(arg.1),
+                        None => return Err(&arg.1),
                         Some(records) => match records.get(&arg.0 .1) {
-                            None => return Err(arg.1),
-                            Some(None) => return Err(arg.1),
-                            Some(Some(approvals)) => match approvals.get(spender) {
-                                None => return Err(arg.1),
-                                Some((_, expire_at)) => {
-                                    if expire_at <= now_sec {
-                                        return Err(arg.1);
-                                    }
+                            None => return Err(&arg.1),
+                            Some(None) => return Err(&arg.1),
+                            Some(Some(approvals)) => {
+                                if !approvals.is_approved(spender, now_sec) {
+                                    return Err(&arg.1);
                                 }
-                            },
+                            }
                         },
                     },
                 }
@@ -695,7 +1860,27 @@ pub mod holder_tokens {
         })
     }
 
-    pub fn update_for_transfer(from: Principal, to: Principal, tid: u32, sid: u32) {
+    pub fn update_for_mint(to: AccountKey, tid: u32, sid: u32) {
+        with_mut(|r| {
+            let mut tokens = r.get(&to).unwrap_or_default();
+            tokens.0.entry(tid).or_default().insert(sid, None);
+            r.insert(to, tokens);
+        });
+    }
+
+    pub fn update_for_burn(from: AccountKey, tid: u32, sid: u32) {
+        with_mut(|r| {
+            if let Some(mut tokens) = r.get(&from) {
+                if tokens.clear_for_transfer(tid, sid) == 0 {
+                    r.remove(&from);
+                } else {
+                    r.insert(from, tokens);
+                }
+            }
+        });
+    }
+
+    pub fn update_for_transfer(from: AccountKey, to: AccountKey, tid: u32, sid: u32) {
         with_mut(|r| {
             if let Some(mut tokens) = r.get(&from) {
                 if tokens.clear_for_transfer(tid, sid) == 0 {
@@ -711,12 +1896,30 @@ pub mod holder_tokens {
         });
     }
 
-    pub fn with<R>(f: impl FnOnce(&StableBTreeMap<Principal, HolderTokens, Memory>) -> R) -> R {
+    // Removes the expired token approvals from `start` until `has_budget` returns false.
+    // Returns the key to resume from, or None if it reached the end.
+    pub fn sweep_expired(
+        start: Option<AccountKey>,
+        now_sec: u64,
+        has_budget: impl Fn() -> bool,
+    ) -> Option<AccountKey> {
+        with_mut(|r| {
+            sweep_map(
+                r,
+                start,
+                |tokens| tokens.prune(now_sec),
+                |_| false,
+                has_budget,
+            )
+        })
+    }
+
+    pub fn with<R>(f: impl FnOnce(&StableBTreeMap<AccountKey, HolderTokens, Memory>) -> R) -> R {
         HOLDER_TOKENS.with(|r| f(&r.borrow()))
     }
 
     pub fn with_mut<R>(
-        f: impl FnOnce(&mut StableBTreeMap<Principal, HolderTokens, Memory>) -> R,
+        f: impl FnOnce(&mut StableBTreeMap<AccountKey, HolderTokens, Memory>) -> R,
     ) -> R {
         HOLDER_TOKENS.with(|r| f(&mut r.borrow_mut()))
     }
@@ -725,91 +1928,80 @@ pub mod holder_tokens {
 pub mod approvals {
     use super::*;
 
-    pub fn is_approved(from: &Principal, spender: &Principal, now_sec: u64) -> bool {
+    pub fn is_approved(from: &AccountKey, spender: &AccountKey, now_sec: u64) -> bool {
         with(|r| {
-            if let Some(approvals) = r.get(from) {
-                if let Some((_, expire_at)) = approvals.0.get(spender) {
-                    return expire_at > &now_sec;
-                }
-            }
-            false
+            r.get(from)
+                .map_or(false, |approvals| approvals.is_approved(spender, now_sec))
         })
     }
 
     // used by atomic_batch_transfers checking
     pub fn find_unapproved<'a>(
-        spender: &Principal,
-        args: &'a [(SftId, &Principal)],
+        spender: &AccountKey,
+        args: &'a [(SftId, AccountKey)],
         now_sec: u64,
-    ) -> Vec<&'a (SftId, &'a Principal)> {
+    ) -> Vec<&'a (SftId, AccountKey)> {
         with(|r| {
             args.iter()
-                .filter(|(_, from)| match r.get(from) {
-                    None => true,
-                    Some(approvals) => match approvals.0.get(spender) {
-                        None => true,
-                        Some((_, expire_at)) => expire_at <= &now_sec,
-                    },
+                .filter(|(_, from)| {
+                    r.get(from)
+                        .map_or(true, |approvals| !approvals.is_approved(spender, now_sec))
                 })
                 .collect()
         })
     }
 
-    pub fn spenders_is_approved(
-        from: &Principal,
-        spenders: &[&Principal],
-        now_sec: u64,
-    ) -> Vec<bool> {
-        with(|r| {
-            let mut res = vec![false; spenders.len()];
-            if let Some(approvals) = r.get(from) {
-                for (i, spender) in spenders.iter().enumerate() {
-                    if let Some((_, expire_at)) = approvals.0.get(spender) {
-                        res[i] = expire_at > &now_sec;
+    // Revokes the collection approval of the `spender`, or all approvals of `from` if `spender` is None.
+    pub fn revoke(
+        from: &AccountKey,
+        spender: Option<&AccountKey>,
+    ) -> Result<(), RevokeCollectionApprovalError> {
+        with_mut(|r| match r.get(from) {
+            None => Err(RevokeCollectionApprovalError::ApprovalDoesNotExist),
+            Some(mut approvals) => match spender {
+                Some(spender) => {
+                    if approvals.0.remove(spender).is_none() {
+                        return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist);
+                    }
+                    if approvals.0.is_empty() {
+                        r.remove(from);
+                    } else {
+                        r.insert(*from, approvals);
                     }
+                    Ok(())
                 }
-            }
-            res
+                None => {
+                    r.remove(from);
+                    Ok(())
+                }
+            },
         })
     }
 
-    pub fn revoke(
-        from: &Principal,
-        spenders: &[Option<Principal>],
-    ) -> Vec<Option<RevokeCollectionApprovalResult>> {
+    // Removes the expired collection approvals from `start` until `has_budget` returns false.
+    // Returns the key to resume from, or None if it reached the end.
+    pub fn sweep_expired(
+        start: Option<AccountKey>,
+        now_sec: u64,
+        has_budget: impl Fn() -> bool,
+    ) -> Option<AccountKey> {
         with_mut(|r| {
-            let mut res: Vec<Option<RevokeCollectionApprovalResult>> = vec![None; spenders.len()];
-            if let Some(mut approvals) = r.get(from) {
-                for (i, spender) in spenders.iter().enumerate() {
-                    match spender {
-                        Some(spender) => {
-                            if approvals.0.remove(spender).is_none() {
-                                res[i] =
-                                    Some(Err(RevokeCollectionApprovalError::ApprovalDoesNotExist));
-                            }
-                        }
-                        None => {
-                            r.remove(from);
-                            return res; // no need to continue
-                        }
-                    }
-                }
-            } else {
-                res.fill(Some(Err(
-                    RevokeCollectionApprovalError::ApprovalDoesNotExist,
-                )));
-            };
-
-            res
+            sweep_map(
+                r,
+                start,
+                |approvals| approvals.prune(now_sec),
+                |approvals| approvals.0.is_empty(),
+                has_budget,
+            )
         })
     }
 
-    pub fn with<R>(f: impl FnOnce(&StableBTreeMap<Principal, Approvals, Memory>) -> R) -> R {
+    pub fn with<R>(f: impl FnOnce(&StableBTreeMap<AccountKey, Approvals, Memory>) -> R) -> R {
         HOLDER_APPROVALS.with(|r| f(&r.borrow()))
     }
 
     pub fn with_mut<R>(
-        f: impl FnOnce(&mut StableBTreeMap<Principal, Approvals, Memory>) -> R,
+        f: impl FnOnce(&mut StableBTreeMap<AccountKey, Approvals, Memory>) -> R,
     ) -> R {
         HOLDER_APPROVALS.with(|r| f(&mut r.borrow_mut()))
     }
@@ -818,29 +2010,120 @@ pub mod approvals {
 pub mod blocks {
     use super::*;
 
+    // The maximum number of blocks copied by one `compact` call.
+    const MAX_COMPACT_BLOCKS: u64 = 10_000;
+
+    fn with_log<R>(c: &Collection, f: impl FnOnce(&StableLog<Block, Memory, Memory>) -> R) -> R {
+        BLOCKS.with(|logs| f(&logs[c.blocks_log as usize].borrow()))
+    }
+
+    // The number of the local blocks that have not been archived.
     pub fn total() -> u64 {
-        BLOCKS.with(|r| r.borrow().len())
+        collection::with(|c| {
+            let archived = c.archived_blocks.saturating_sub(c.blocks_offset);
+            with_log(c, |r| r.len()).saturating_sub(archived)
+        })
     }
 
     pub fn append(tx: Transaction) -> Result<u64, String> {
         collection::with_mut(|c| {
-            let blk = Block::new(c.last_block_hash, tx);
-            let _ = BLOCKS
-                .with(|r| r.borrow_mut().append(&blk))
-                .map_err(|err| format!("failed to append transaction log, error {:?}", err))?;
-            let i = if let Some(i) = c.last_block_index {
-                i + 1
-            } else {
-                0
-            };
-            c.last_block_index = Some(i);
-            c.last_block_hash = Some(blk.hash());
+            let i = push(c, tx)?;
             ic_cdk::api::set_certified_data(&c.root_hash());
-
             Ok(i)
         })
     }
 
+    pub(super) fn push(c: &mut Collection, tx: Transaction) -> Result<u64, String> {
+        let blk = Block::new(c.last_block_hash, tx);
+        BLOCKS
+            .with(|logs| logs[c.blocks_log as usize].borrow_mut().append(&blk))
+            .map_err(|err| format!("failed to append transaction log, error {:?}", err))?;
+        let i = if let Some(i) = c.last_block_index {
+            i + 1
+        } else {
+            0
+        };
+        c.last_block_index = Some(i);
+        c.last_block_hash = Some(blk.hash());
+        Ok(i)
+    }
+
+    // Returns the local blocks that have not been archived in [offset, offset + length),
+    // the offset 0 is the first block not archived.
+    pub fn get_local(offset: u64, length: u64) -> Vec<Block> {
+        collection::with(|c| {
+            let archived = c.archived_blocks.saturating_sub(c.blocks_offset);
+            with_log(c, |logs| {
+                let start = archived.saturating_add(offset);
+                (start..start.saturating_add(length).min(logs.len()))
+                    .filter_map(|i| logs.get(i))
+                    .collect()
+            })
+        })
+    }
+
+    // Drops the archived blocks from the local log. The blocks not archived are copied to the other log
+    // in bounded steps, the blocks appended meanwhile are copied by the next steps. When all are copied,
+    // the other log becomes the active one and the old log is reset, so the memory of the archived blocks is reused.
+    // It starts when the archived blocks are no fewer than the blocks not archived, so each block is copied
+    // a bounded number of times.
+    pub fn compact() {
+        compact_with(MAX_COMPACT_BLOCKS)
+    }
+
+    pub(super) fn compact_with(max_blocks: u64) {
+        collection::with_mut(|c| {
+            let active = c.blocks_log as usize;
+            let other = 1 - active;
+            BLOCKS.with(|logs| {
+                let end = c.blocks_offset + logs[active].borrow().len();
+                let task = match c.blocks_compaction.take() {
+                    Some(task) => task,
+                    None => {
+                        let archived = c.archived_blocks.saturating_sub(c.blocks_offset);
+                        if archived == 0 || archived < end - c.archived_blocks {
+                            return;
+                        }
+                        *logs[other].borrow_mut() = new_log(other);
+                        BlocksCompaction {
+                            start: c.archived_blocks,
+                            next: c.archived_blocks,
+                        }
+                    }
+                };
+
+                let stop = end.min(task.next.saturating_add(max_blocks));
+                {
+                    let src = logs[active].borrow();
+                    let dst = logs[other].borrow_mut();
+                    for i in task.next..stop {
+                        let blk = src.get(i - c.blocks_offset).expect("block not found");
+                        dst.append(&blk).expect("failed to copy block");
+                    }
+                }
+                if stop < end {
+                    c.blocks_compaction = Some(BlocksCompaction {
+                        start: task.start,
+                        next: stop,
+                    });
+                    return;
+                }
+
+                *logs[active].borrow_mut() = new_log(active);
+                c.blocks_log = other as u8;
+                c.blocks_offset = task.start;
+            })
+        });
+    }
+
+    fn new_log(i: usize) -> StableLog<Block, Memory, Memory> {
+        let (index, data) = BLOCKS_MEMORY_IDS[i];
+        StableLog::new(
+            MEMORY_MANAGER.with_borrow(|m| m.get(index)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(data)),
+        )
+    }
+
     pub fn get_blocks(args: Vec<GetBlocksRequest>) -> GetBlocksResult {
         const MAX_BLOCKS_PER_RESPONSE: u64 = 100;
 
@@ -852,24 +2135,38 @@ pub mod blocks {
                 blocks: vec![],
                 archived_blocks: vec![],
             },
-            Some(last_block_index) => BLOCKS.with(|r| {
-                let logs = r.borrow();
+            Some(last_block_index) => with_log(&coll, |logs| {
                 let mut blocks = vec![];
+                let mut archived: BTreeMap<Principal, Vec<GetBlocksRequest>> = BTreeMap::new();
                 for arg in args {
-                    let (start, length) = arg
+                    let (mut start, mut length) = arg
                         .as_start_and_length()
                         .unwrap_or_else(|msg| ic_cdk::api::trap(&msg));
                     if start < coll.archived_blocks {
-                        break;
+                        let end = start.saturating_add(length).min(coll.archived_blocks);
+                        for archive in &coll.archives {
+                            let (s, e) = (start.max(archive.start), end.min(archive.end));
+                            if s < e {
+                                archived.entry(archive.canister_id).or_default().push(
+                                    GetBlocksRequest {
+                                        start: Nat::from(s),
+                                        length: Nat::from(e - s),
+                                    },
+                                );
+                            }
+                        }
+                        length -= end - start;
+                        start = end;
                     }
-                    let offset = start - coll.archived_blocks;
+
+                    let offset = start - coll.blocks_offset;
                     let logs_len = logs.len();
-                    if offset >= logs_len {
-                        break;
+                    if length == 0 || offset >= logs_len {
+                        continue;
                     }
                     let max_length = MAX_BLOCKS_PER_RESPONSE.saturating_sub(blocks.len() as u64);
                     if max_length == 0 {
-                        break;
+                        continue;
                     }
                     let length = max_length.min(length).min(logs_len - offset);
                     for i in 0..length {
@@ -883,34 +2180,1574 @@ pub mod blocks {
                             }
                         }
                     }
-
-                    if blocks.len() as u64 >= MAX_BLOCKS_PER_RESPONSE {
-                        break;
-                    }
                 }
 
                 GetBlocksResult {
                     log_length: Nat::from(last_block_index + 1),
                     blocks,
-                    archived_blocks: vec![],
+                    archived_blocks: archived
+                        .into_iter()
+                        .map(|(canister_id, args)| ArchivedBlocks {
+                            args,
+                            callback: QueryArchiveFn::new(canister_id, "icrc3_get_blocks"),
+                        })
+                        .collect(),
                 }
             }),
         }
     }
 }
 
+pub mod tx_dedup {
+    use super::*;
+
+    const MAX_PRUNE_PER_CALL: usize = 100;
+
+    // Returns the block index of the transaction with the same hash in the deduplication window.
+    pub fn find(hash: &[u8; 32], now: u64, settings: &Settings) -> Option<u64> {
+        let window = (settings.tx_window + settings.permitted_drift) * SECOND;
+        prune(now.saturating_sub(window));
+        TX_DEDUP.with(|r| r.borrow().get(hash).map(|(_, idx)| idx))
+    }
+
+    // Checks the transactions of an atomic batch before any of them is applied, as a duplicate would be
+    // rejected after the other transactions are applied. Returns the block index of the first duplicate,
+    // or Err(None) if the batch has the same transaction twice.
+    pub fn check_batch(
+        hashes: &[[u8; 32]],
+        now: u64,
+        settings: &Settings,
+    ) -> Result<(), Option<u64>> {
+        let mut seen = BTreeSet::new();
+        for hash in hashes {
+            if let Some(idx) = find(hash, now, settings) {
+                return Err(Some(idx));
+            }
+            if !seen.insert(hash) {
+                return Err(None);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn insert(hash: [u8; 32], created_at_time: u64, block_idx: u64) {
+        TX_DEDUP.with(|r| r.borrow_mut().insert(hash, (created_at_time, block_idx)));
+        TX_DEDUP_QUEUE.with(|r| r.borrow_mut().insert((created_at_time, hash), ()));
+    }
+
+    // Removes the entries created before `before`, at most MAX_PRUNE_PER_CALL entries at a time.
+    fn prune(before: u64) {
+        TX_DEDUP_QUEUE.with(|q| {
+            let mut q = q.borrow_mut();
+            let expired: Vec<(u64, [u8; 32])> = q
+                .range(..(before, [0u8; 32]))
+                .take(MAX_PRUNE_PER_CALL)
+                .map(|(k, _)| k)
+                .collect();
+            TX_DEDUP.with(|r| {
+                let mut r = r.borrow_mut();
+                for key in expired {
+                    q.remove(&key);
+                    r.remove(&key.1);
+                }
+            });
+        });
+    }
+}
+
+pub mod archives {
+    use super::*;
+
+    pub fn wasm() -> Vec<u8> {
+        ARCHIVE_WASM.with(|r| r.borrow().get().clone())
+    }
+
+    pub fn set_wasm(wasm: Vec<u8>) {
+        ARCHIVE_WASM.with(|r| {
+            r.borrow_mut()
+                .set(wasm)
+                .expect("failed to set ARCHIVE_WASM data");
+        });
+    }
+}
+
 pub mod assets {
     use super::*;
+    use std::io::Read;
+
+    // The content encodings of the asset variants, in order of preference.
+    pub const CONTENT_ENCODINGS: [(u8, &str); 2] = [(2, "br"), (1, "gzip")];
+
+    // The size of the chunks, a chunk is the largest body of a response.
+    pub const ASSET_CHUNK_SIZE: usize = 1_800_000;
+
+    // The maximum size of an asset that can have encoded variants, as `set_encoding` decodes a variant
+    // in one call to verify it.
+    pub const MAX_ENCODED_ASSET_SIZE: u64 = 8 * 1024 * 1024;
 
     pub fn total() -> u64 {
-        ASSETS.with(|r| r.borrow().len())
+        ASSET_META.with(|r| r.borrow().len()) + ASSETS.with(|r| r.borrow().len())
+    }
+
+    pub fn encoding_code(content_encoding: &str) -> Option<u8> {
+        CONTENT_ENCODINGS
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(content_encoding))
+            .map(|(code, _)| *code)
+    }
+
+    // Links the encoded variant to the asset, the variant is an asset committed with the hash of
+    // the encoded content and it is referenced by the asset. None unlinks the variant.
+    // The variant should decode to the content of the asset, no larger than MAX_ENCODED_ASSET_SIZE.
+    pub fn set_encoding(hash: [u8; 32], code: u8, encoded: Option<[u8; 32]>) -> Result<(), String> {
+        let mut meta = meta(&hash).ok_or_else(|| "asset not found".to_string())?;
+        if let Some(encoded) = encoded {
+            if meta.size > MAX_ENCODED_ASSET_SIZE {
+                return Err(format!(
+                    "the assets larger than {} bytes can not have encoded variants",
+                    MAX_ENCODED_ASSET_SIZE
+                ));
+            }
+            if encoded == hash || !ASSET_META.with(|r| r.borrow().contains_key(&encoded)) {
+                return Err("encoded asset not found".to_string());
+            }
+            if meta.encodings.get(&code) != Some(&encoded) {
+                verify_encoding(&hash, &meta, code, encoded)?;
+            }
+        }
+
+        let prev = match encoded {
+            Some(encoded) => meta.encodings.insert(code, encoded),
+            None => meta.encodings.remove(&code),
+        };
+        if prev == encoded {
+            return Ok(());
+        }
+
+        ASSET_META.with(|r| r.borrow_mut().insert(hash, meta));
+        if let Some(encoded) = encoded {
+            add_ref(encoded);
+        }
+        if let Some(prev) = prev {
+            release(&prev);
+        }
+        Ok(())
+    }
+
+    // Decodes the encoded asset and checks that the decoded content is the content of the asset.
+    fn verify_encoding(
+        hash: &[u8; 32],
+        meta: &AssetMeta,
+        code: u8,
+        encoded: [u8; 32],
+    ) -> Result<(), String> {
+        let reader = ChunkReader {
+            hash: encoded,
+            index: 0,
+            chunk: Vec::new(),
+            pos: 0,
+        };
+        let mut decoder: Box<dyn Read> = match code {
+            1 => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+            2 => Box::new(brotli_decompressor::Decompressor::new(reader, 4096)),
+            _ => return Err(format!("unsupported content encoding {}", code)),
+        };
+
+        let mismatch = || "the decoded content does not match the asset".to_string();
+        let mut sha3 = Sha3_256::new();
+        let mut size = 0u64;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = decoder
+                .read(&mut buf)
+                .map_err(|err| format!("failed to decode the encoded asset: {}", err))?;
+            if n == 0 {
+                break;
+            }
+            size += n as u64;
+            if size > meta.size {
+                return Err(mismatch());
+            }
+            sha3.update(&buf[..n]);
+        }
+        if size != meta.size || <[u8; 32]>::from(sha3.finalize()) != *hash {
+            return Err(mismatch());
+        }
+        Ok(())
+    }
+
+    // Reads the content of an asset chunk by chunk.
+    struct ChunkReader {
+        hash: [u8; 32],
+        index: u32,
+        chunk: Vec<u8>,
+        pos: usize,
+    }
+
+    impl Read for ChunkReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pos == self.chunk.len() {
+                match chunk(&self.hash, self.index) {
+                    Some(chunk) => {
+                        self.chunk = chunk;
+                        self.pos = 0;
+                        self.index += 1;
+                    }
+                    None => return Ok(0),
+                }
+            }
+            let n = buf.len().min(self.chunk.len() - self.pos);
+            buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    // Inserts the asset if it does not exist, the asset is orphaned until it is referenced by a token.
+    // The content type of an existing asset is not changed, see `set_content_type`.
+    pub fn insert(hash: [u8; 32], content_type: &str, content: &[u8]) -> Result<(), String> {
+        if exists(&hash) {
+            return Ok(());
+        }
+
+        let mut writer = Writer::new(hash, content_type.to_string());
+        writer.write(content);
+        writer.finish()
+    }
+
+    // Sets the content type served by "/asset/{hash}", returns true if it is changed.
+    pub fn set_content_type(hash: &[u8; 32], content_type: &str) -> bool {
+        ASSET_META.with(|r| {
+            let mut r = r.borrow_mut();
+            match r.get(hash) {
+                Some(mut meta) if meta.content_type != content_type => {
+                    meta.content_type = content_type.to_string();
+                    r.insert(*hash, meta);
+                    true
+                }
+                _ => false,
+            }
+        })
+    }
+
+    pub fn exists(hash: &[u8; 32]) -> bool {
+        ASSET_META.with(|r| r.borrow().contains_key(hash))
+            || ASSETS.with(|r| r.borrow().contains_key(hash))
+    }
+
+    pub fn meta(hash: &[u8; 32]) -> Option<AssetMeta> {
+        ASSET_META.with(|r| r.borrow().get(hash))
+    }
+
+    pub fn size(hash: &[u8; 32]) -> Option<u64> {
+        match meta(hash) {
+            Some(meta) => Some(meta.size),
+            None => ASSETS.with(|r| r.borrow().get(hash).map(|c| c.len() as u64)),
+        }
+    }
+
+    // Returns the chunk at `index`, the assets not migrated yet are split into chunks on reading.
+    pub fn chunk(hash: &[u8; 32], index: u32) -> Option<Vec<u8>> {
+        if let Some(chunk) = ASSET_CHUNKS.with(|r| r.borrow().get(&(*hash, index))) {
+            return Some(chunk);
+        }
+        if let Some(id) = meta(hash).and_then(|meta| meta.upload_id) {
+            return UPLOAD_CHUNKS.with(|r| r.borrow().get(&(id, index)));
+        }
+
+        let content = ASSETS.with(|r| r.borrow().get(hash))?;
+        content
+            .chunks(ASSET_CHUNK_SIZE)
+            .nth(index as usize)
+            .map(|chunk| chunk.to_vec())
+    }
+
+    // Writes the content of an asset in chunks and computes its hashes.
+    pub struct Writer {
+        hash: [u8; 32],
+        buf: Vec<u8>,
+        meta: AssetMeta,
+        sha3: Sha3_256,
+        sha256: Sha256,
+    }
+
+    impl Writer {
+        pub fn new(hash: [u8; 32], content_type: String) -> Self {
+            Writer {
+                hash,
+                buf: Vec::new(),
+                meta: AssetMeta {
+                    content_type,
+                    ..Default::default()
+                },
+                sha3: Sha3_256::new(),
+                sha256: Sha256::new(),
+            }
+        }
+
+        pub fn write(&mut self, mut data: &[u8]) {
+            while !data.is_empty() {
+                let n = (ASSET_CHUNK_SIZE - self.buf.len()).min(data.len());
+                self.buf.extend_from_slice(&data[..n]);
+                data = &data[n..];
+                if self.buf.len() == ASSET_CHUNK_SIZE {
+                    self.flush();
+                }
+            }
+        }
+
+        fn flush(&mut self) {
+            let chunk = std::mem::take(&mut self.buf);
+            self.sha3.update(&chunk);
+            self.sha256.update(&chunk);
+            self.meta.chunks.push(Sha256::digest(&chunk).into());
+            self.meta.size += chunk.len() as u64;
+            let index = self.meta.chunks.len() as u32 - 1;
+            ASSET_CHUNKS.with(|r| r.borrow_mut().insert((self.hash, index), chunk));
+        }
+
+        // Verifies the SHA3-256 hash and saves the asset, the written chunks are removed on mismatch.
+        pub fn finish(mut self) -> Result<(), String> {
+            if !self.buf.is_empty() || self.meta.chunks.is_empty() {
+                self.flush();
+            }
+
+            if <[u8; 32]>::from(self.sha3.finalize()) != self.hash {
+                remove_chunks(&self.hash, self.meta.chunks.len() as u32);
+                return Err("asset hash mismatch".to_string());
+            }
+
+            self.meta.sha256 = self.sha256.finalize().into();
+            save(self.hash, self.meta);
+            Ok(())
+        }
+    }
+
+    // Saves the meta of the asset whose chunks are stored, the asset is orphaned until it is referenced by a token.
+    pub(super) fn save(hash: [u8; 32], meta: AssetMeta) {
+        ASSET_META.with(|r| r.borrow_mut().insert(hash, meta));
+        ASSET_REFS.with(|r| {
+            let mut r = r.borrow_mut();
+            if !r.contains_key(&hash) {
+                r.insert(hash, 0);
+            }
+        });
+    }
+
+    fn remove_chunks(hash: &[u8; 32], chunks: u32) {
+        ASSET_CHUNKS.with(|r| {
+            let mut r = r.borrow_mut();
+            for index in 0..chunks {
+                r.remove(&(*hash, index));
+            }
+        });
+    }
+
+    pub fn has_legacy() -> bool {
+        ASSETS.with(|r| !r.borrow().is_empty())
+    }
+
+    // Moves the assets stored as whole contents into chunks, one asset at a time while `has_budget` returns true.
+    // The content types are looked up by `content_type_of`, the assets not referenced by tokens are
+    // "application/octet-stream". `migrated` is called with each moved asset. Returns true when all the assets are moved.
+    pub fn migrate(
+        content_type_of: impl Fn(&[u8; 32]) -> Option<String>,
+        mut migrated: impl FnMut(&[u8; 32]),
+        has_budget: impl Fn() -> bool,
+    ) -> bool {
+        while has_budget() {
+            let hash = match ASSETS.with(|r| r.borrow().first_key_value().map(|(hash, _)| hash)) {
+                Some(hash) => hash,
+                None => return true,
+            };
+
+            let content = ASSETS
+                .with(|r| r.borrow_mut().remove(&hash))
+                .unwrap_or_default();
+            if !ASSET_META.with(|r| r.borrow().contains_key(&hash)) {
+                let content_type = content_type_of(&hash)
+                    .unwrap_or_else(|| "application/octet-stream".to_string());
+                let mut writer = Writer::new(hash, content_type);
+                writer.write(&content);
+                // the legacy assets were verified when they were stored
+                match writer.finish() {
+                    Ok(_) => migrated(&hash),
+                    Err(err) => ic_cdk::print(format!(
+                        "failed to migrate asset {}: {}",
+                        hex::encode(hash),
+                        err
+                    )),
+                }
+            }
+        }
+        ASSETS.with(|r| r.borrow().is_empty())
+    }
+
+    pub fn refs(hash: &[u8; 32]) -> u32 {
+        ASSET_REFS.with(|r| r.borrow().get(hash).unwrap_or(0))
+    }
+
+    pub fn add_ref(hash: [u8; 32]) {
+        ASSET_REFS.with(|r| {
+            let mut r = r.borrow_mut();
+            let refs = r.get(&hash).unwrap_or(0);
+            r.insert(hash, refs + 1);
+        });
+    }
+
+    // Releases a reference of the asset, removes the asset when the last reference is released.
+    // Returns true if the asset is removed.
+    pub fn release(hash: &[u8; 32]) -> bool {
+        let refs = ASSET_REFS.with(|r| {
+            let mut r = r.borrow_mut();
+            let refs = r.get(hash).unwrap_or(0).saturating_sub(1);
+            r.insert(*hash, refs);
+            refs
+        });
+        if refs == 0 {
+            remove(hash);
+        }
+        refs == 0
+    }
+
+    // Returns the assets that are not referenced by any token, e.g. the expired uploads never used.
+    pub fn orphans(prev: Option<[u8; 32]>, take: usize) -> Vec<[u8; 32]> {
+        ASSET_REFS.with(|r| {
+            let r = r.borrow();
+            let range = (
+                prev.map_or(std::ops::Bound::Unbounded, std::ops::Bound::Excluded),
+                std::ops::Bound::Unbounded,
+            );
+            r.range(range)
+                .filter(|(_, refs)| *refs == 0)
+                .take(take)
+                .map(|(hash, _)| hash)
+                .collect()
+        })
+    }
+
+    // Removes the asset, its encoded variants and its reference count.
+    pub fn remove(hash: &[u8; 32]) {
+        ASSETS.with(|r| r.borrow_mut().remove(hash));
+        ASSET_REFS.with(|r| r.borrow_mut().remove(hash));
+        if let Some(meta) = ASSET_META.with(|r| r.borrow_mut().remove(hash)) {
+            match meta.upload_id {
+                Some(id) => uploads::remove_chunks(id),
+                None => remove_chunks(hash, meta.chunks.len() as u32),
+            }
+            for encoded in meta.encodings.values() {
+                release(encoded);
+            }
+        }
+    }
+}
+
+pub mod uploads {
+    use super::*;
+
+    // The maximum size of an asset uploaded in chunks.
+    pub const MAX_ASSET_SIZE: u64 = 64 * 1024 * 1024;
+    // Uploads not committed in time are removed.
+    pub const UPLOAD_EXPIRY_SEC: u64 = 24 * 60 * 60;
+    const MAX_PRUNE_PER_CALL: usize = 10;
+
+    pub fn create(
+        owner: Principal,
+        asset_hash: [u8; 32],
+        asset_size: u64,
+        content_type: String,
+        now_sec: u64,
+    ) -> u32 {
+        prune(now_sec.saturating_sub(UPLOAD_EXPIRY_SEC));
+        UPLOADS.with(|r| {
+            let mut r = r.borrow_mut();
+            // the uploads created before the counter was tracked
+            let last = r.last_key_value().map(|(id, _)| id).unwrap_or(0);
+            let id = collection::with_mut(|c| {
+                c.last_upload_id = c.last_upload_id.max(last) + 1;
+                c.last_upload_id
+            });
+            r.insert(
+                id,
+                Upload {
+                    owner,
+                    asset_hash,
+                    asset_size,
+                    received: 0,
+                    created_at: now_sec,
+                    content_type,
+                    committed: false,
+                    chunks: Vec::new(),
+                },
+            );
+            id
+        })
+    }
+
+    // Stores and hashes the chunk at `index`. The chunks are uploaded in order of index,
+    // each chunk except the last one has ASSET_CHUNK_SIZE bytes so that they are the asset chunks.
+    pub fn put_chunk(
+        owner: &Principal,
+        id: u32,
+        index: u32,
+        content: Vec<u8>,
+    ) -> Result<(), String> {
+        let mut upload = get(owner, id)?;
+        if index as usize != upload.chunks.len() {
+            return Err(format!("expected chunk {}", upload.chunks.len()));
+        }
+        let received = upload.received + content.len() as u64;
+        if received > upload.asset_size {
+            return Err("chunks exceed the asset size".to_string());
+        }
+        if received < upload.asset_size && content.len() != assets::ASSET_CHUNK_SIZE {
+            return Err(format!(
+                "chunk size should be {} bytes except the last chunk",
+                assets::ASSET_CHUNK_SIZE
+            ));
+        }
+
+        UPLOAD_HASHERS.with(|r| {
+            let mut r = r.borrow_mut();
+            if index == 0 {
+                r.insert(id, (Sha3_256::new(), Sha256::new()));
+            }
+            let (sha3, sha256) = r.get_mut(&id).ok_or_else(interrupted)?;
+            sha3.update(&content);
+            sha256.update(&content);
+            Ok::<(), String>(())
+        })?;
+        upload.chunks.push(Sha256::digest(&content).into());
+        upload.received = received;
+        UPLOAD_CHUNKS.with(|r| r.borrow_mut().insert((id, index), content));
+        UPLOADS.with(|r| r.borrow_mut().insert(id, upload));
+        Ok(())
+    }
+
+    // Verifies the SHA3-256 hash of the chunks hashed by `put_chunk`, the chunks become the asset chunks
+    // without being copied. An upload that mismatches the hash is removed. The upload takes a reference
+    // to the asset until it expires, so that the asset is not removed as an orphan before a token references it.
+    pub fn commit(owner: &Principal, id: u32, now_sec: u64) -> Result<[u8; 32], String> {
+        let mut upload = get(owner, id)?;
+        if upload.received != upload.asset_size {
+            return Err(format!(
+                "incomplete upload, received {} of {} bytes",
+                upload.received, upload.asset_size
+            ));
+        }
+
+        let (sha3, sha256) = UPLOAD_HASHERS
+            .with(|r| r.borrow_mut().remove(&id))
+            .ok_or_else(interrupted)?;
+        if <[u8; 32]>::from(sha3.finalize()) != upload.asset_hash {
+            remove(id);
+            return Err("asset hash mismatch".to_string());
+        }
+
+        if assets::exists(&upload.asset_hash) {
+            remove_chunks(id);
+        } else {
+            let meta = AssetMeta {
+                content_type: upload.content_type.clone(),
+                size: upload.asset_size,
+                sha256: sha256.finalize().into(),
+                chunks: std::mem::take(&mut upload.chunks),
+                upload_id: Some(id),
+                ..Default::default()
+            };
+            assets::save(upload.asset_hash, meta);
+        }
+        assets::add_ref(upload.asset_hash);
+        upload.committed = true;
+        upload.created_at = now_sec;
+        UPLOADS.with(|r| r.borrow_mut().insert(id, upload.clone()));
+        Ok(upload.asset_hash)
+    }
+
+    fn get(owner: &Principal, id: u32) -> Result<Upload, String> {
+        match UPLOADS.with(|r| r.borrow().get(&id)) {
+            Some(upload) if &upload.owner == owner && !upload.committed => Ok(upload),
+            _ => Err("upload not found".to_string()),
+        }
+    }
+
+    // The hashers of the upload are lost on upgrade, the chunks can not be hashed in order any more.
+    fn interrupted() -> String {
+        "the upload is interrupted by an upgrade, create a new upload".to_string()
+    }
+
+    // Removes the upload, the reference of a committed upload is released.
+    // The chunks of a committed upload belong to the asset and are removed with it.
+    fn remove(id: u32) {
+        UPLOAD_HASHERS.with(|r| r.borrow_mut().remove(&id));
+        match UPLOADS.with(|r| r.borrow_mut().remove(&id)) {
+            Some(upload) if upload.committed => {
+                assets::release(&upload.asset_hash);
+            }
+            _ => remove_chunks(id),
+        }
+    }
+
+    pub(super) fn remove_chunks(id: u32) {
+        UPLOAD_CHUNKS.with(|r| {
+            let mut r = r.borrow_mut();
+            let keys: Vec<(u32, u32)> = r.range((id, 0)..=(id, u32::MAX)).map(|(k, _)| k).collect();
+            for key in keys {
+                r.remove(&key);
+            }
+        });
+    }
+
+    // Removes the uploads created before `before`, at most MAX_PRUNE_PER_CALL uploads at a time.
+    fn prune(before: u64) {
+        let expired: Vec<u32> = UPLOADS.with(|r| {
+            r.borrow()
+                .iter()
+                .filter(|(_, upload)| upload.created_at < before)
+                .take(MAX_PRUNE_PER_CALL)
+                .map(|(id, _)| id)
+                .collect()
+        });
+        for id in expired {
+            remove(id);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    #[test]
+    fn test_tx_dedup() {
+        let settings = Settings {
+            tx_window: 10,
+            permitted_drift: 2,
+            ..Default::default()
+        };
+        let now = 100 * SECOND;
+        assert_eq!(tx_dedup::find(&[1u8; 32], now, &settings), None);
+
+        tx_dedup::insert([1u8; 32], now, 7);
+        tx_dedup::insert([2u8; 32], now + SECOND, 8);
+        assert_eq!(tx_dedup::find(&[1u8; 32], now, &settings), Some(7));
+        assert_eq!(tx_dedup::find(&[2u8; 32], now, &settings), Some(8));
+        assert_eq!(tx_dedup::find(&[3u8; 32], now, &settings), None);
+
+        // the batch is checked against the recorded transactions and itself
+        let batch = |hashes: &[[u8; 32]]| tx_dedup::check_batch(hashes, now, &settings);
+        assert_eq!(batch(&[[3u8; 32], [4u8; 32]]), Ok(()));
+        assert_eq!(batch(&[[3u8; 32], [2u8; 32]]), Err(Some(8)));
+        assert_eq!(batch(&[[3u8; 32], [4u8; 32], [3u8; 32]]), Err(None));
+
+        // still in the window of tx_window + permitted_drift
+        assert_eq!(
+            tx_dedup::find(&[1u8; 32], now + 12 * SECOND, &settings),
+            Some(7)
+        );
+        // the first entry is pruned
+        assert_eq!(
+            tx_dedup::find(&[1u8; 32], now + 13 * SECOND, &settings),
+            None
+        );
+        assert_eq!(
+            tx_dedup::find(&[2u8; 32], now + 13 * SECOND, &settings),
+            Some(8)
+        );
+        assert_eq!(
+            tx_dedup::find(&[2u8; 32], now + 14 * SECOND, &settings),
+            None
+        );
+        TX_DEDUP.with(|r| assert!(r.borrow().is_empty()));
+        TX_DEDUP_QUEUE.with(|r| assert!(r.borrow().is_empty()));
+    }
+
+    #[test]
+    fn test_blocks_compact() {
+        let to = Account::from(Principal::self_authenticating(b"holder public key"));
+        let push = |n: u64| {
+            collection::with_mut(|c| {
+                for _ in 0..n {
+                    let i = c.last_block_index.map_or(0, |i| i + 1);
+                    let tx = Transaction::transfer(i * SECOND, SftId(1, 1).to_u64(), to, to, None);
+                    blocks::push(c, tx).unwrap();
+                }
+            })
+        };
+        push(10);
+        let all = blocks::get_local(0, 100);
+        assert_eq!(all.len(), 10);
+
+        // nothing is archived
+        blocks::compact_with(2);
+        collection::with(|c| assert!(c.blocks_compaction.is_none()));
+
+        // the blocks [0, 6) are archived, and blocks are appended between the compaction steps
+        collection::with_mut(|c| c.archived_blocks = 6);
+        blocks::compact_with(2);
+        collection::with(|c| {
+            assert_eq!(c.blocks_log, 0);
+            assert_eq!(c.blocks_compaction.as_ref().unwrap().next, 8);
+        });
+        push(3);
+        collection::with_mut(|c| c.archived_blocks = 8);
+        blocks::compact_with(2);
+        collection::with(|c| assert_eq!(c.blocks_compaction.as_ref().unwrap().next, 10));
+        assert_eq!(blocks::total(), 5);
+        assert_eq!(blocks::get_local(0, 2), all[8..10]);
+
+        blocks::compact_with(10);
+        collection::with(|c| {
+            assert_eq!(c.blocks_log, 1);
+            assert_eq!(c.blocks_offset, 6);
+            assert!(c.blocks_compaction.is_none());
+        });
+        BLOCKS.with(|logs| {
+            assert_eq!(logs[0].borrow().len(), 0);
+            assert_eq!(logs[1].borrow().len(), 7);
+        });
+        assert_eq!(blocks::total(), 5);
+        let local = blocks::get_local(0, 100);
+        assert_eq!(local.len(), 5);
+        assert_eq!(local[..2], all[8..10]);
+
+        // the blocks are appended to the new active log
+        push(1);
+        assert_eq!(blocks::total(), 6);
+        assert_eq!(blocks::get_local(5, 1).len(), 1);
+
+        // the archived blocks [6, 8) are fewer than the blocks not archived
+        blocks::compact();
+        collection::with(|c| assert!(c.blocks_compaction.is_none()));
+        // they are dropped when the archiving catches up
+        collection::with_mut(|c| c.archived_blocks = 12);
+        blocks::compact();
+        collection::with(|c| {
+            assert_eq!(c.blocks_log, 0);
+            assert_eq!(c.blocks_offset, 12);
+        });
+        assert_eq!(blocks::total(), 2);
+        let local = blocks::get_local(0, 100);
+        assert_eq!(
+            local[1].clone().hash(),
+            collection::with(|c| c.last_block_hash.unwrap())
+        );
     }
 
-    pub fn with<R>(f: impl FnOnce(&StableBTreeMap<[u8; 32], Vec<u8>, Memory>) -> R) -> R {
-        ASSETS.with(|r| f(&r.borrow()))
+    #[test]
+    fn test_get_blocks() {
+        let to = Account::from(Principal::self_authenticating(b"holder public key"));
+        collection::with_mut(|c| {
+            for i in 0..10u64 {
+                let tx = Transaction::transfer(i * SECOND, SftId(1, 1).to_u64(), to, to, None);
+                blocks::push(c, tx).unwrap();
+            }
+            // the blocks [0, 4) and [4, 6) are archived by two archives
+            for (id, start, end) in [(1u8, 0, 4), (2, 4, 6)] {
+                c.archives.push(Archive {
+                    canister_id: Principal::from_slice(&[id]),
+                    start,
+                    end,
+                    pending: false,
+                });
+            }
+            c.archived_blocks = 6;
+        });
+        // the archived blocks are dropped from the local log
+        blocks::compact_with(10);
+        collection::with(|c| assert_eq!(c.blocks_offset, 6));
+
+        let request = |start: u64, length: u64| GetBlocksRequest {
+            start: Nat::from(start),
+            length: Nat::from(length),
+        };
+        let ids = |res: &GetBlocksResult| -> Vec<Nat> {
+            res.blocks.iter().map(|b| b.id.clone()).collect()
+        };
+        let archived = |res: &GetBlocksResult| -> Vec<Vec<(Nat, Nat)>> {
+            res.archived_blocks
+                .iter()
+                .map(|a| {
+                    a.args
+                        .iter()
+                        .map(|r| (r.start.clone(), r.length.clone()))
+                        .collect()
+                })
+                .collect()
+        };
+
+        // the range across the archives and the local log is split
+        let res = blocks::get_blocks(vec![request(2, 6)]);
+        assert_eq!(res.log_length, Nat::from(10u64));
+        assert_eq!(ids(&res), vec![Nat::from(6u64), Nat::from(7u64)]);
+        assert_eq!(
+            archived(&res),
+            vec![
+                vec![(Nat::from(2u64), Nat::from(2u64))],
+                vec![(Nat::from(4u64), Nat::from(2u64))],
+            ]
+        );
+
+        // the archived ranges of the requests are grouped by archive
+        let res = blocks::get_blocks(vec![request(0, 1), request(3, 2), request(9, 100)]);
+        assert_eq!(ids(&res), vec![Nat::from(9u64)]);
+        assert_eq!(
+            archived(&res),
+            vec![
+                vec![
+                    (Nat::from(0u64), Nat::from(1u64)),
+                    (Nat::from(3u64), Nat::from(1u64)),
+                ],
+                vec![(Nat::from(4u64), Nat::from(1u64))],
+            ]
+        );
+
+        // the ranges out of the log are empty
+        let res = blocks::get_blocks(vec![request(10, 5), request(6, 0)]);
+        assert!(res.blocks.is_empty());
+        assert!(res.archived_blocks.is_empty());
+    }
+
+    #[test]
+    fn test_account_key() {
+        let owner = Principal::self_authenticating(b"owner public key");
+        for key in [
+            AccountKey::from(ANONYMOUS),
+            AccountKey::from(owner),
+            AccountKey::new(owner, Some([1u8; 32])),
+        ] {
+            assert_eq!(AccountKey::from_bytes(key.to_bytes()), key);
+            let data = crate::utils::to_cbor_bytes(&key);
+            let key2: AccountKey = from_reader(&data[..]).unwrap();
+            assert_eq!(key2, key);
+        }
+
+        // the default subaccount is normalized
+        let key = AccountKey::new(owner, Some([0u8; 32]));
+        assert_eq!(key, AccountKey::from(owner));
+        assert_eq!(key.to_bytes().as_ref(), owner.as_slice());
+
+        // the principals stored before subaccounts were supported
+        let key = AccountKey::from_bytes(Cow::Borrowed(owner.as_slice()));
+        assert_eq!(key, AccountKey::from(owner));
+        let data = crate::utils::to_cbor_bytes(&vec![owner, ANONYMOUS]);
+        let keys: Vec<AccountKey> = from_reader(&data[..]).unwrap();
+        assert_eq!(
+            keys,
+            vec![AccountKey::from(owner), AccountKey::from(ANONYMOUS)]
+        );
+
+        assert!(AccountKey::decode(&[0u8; 30]).is_err());
+    }
+
+    #[test]
+    fn test_init_roles() {
+        let minter = Principal::self_authenticating(b"minter public key");
+        let manager = Principal::self_authenticating(b"manager public key");
+        let legacy = Collection {
+            minters: BTreeSet::from([minter, manager]),
+            managers: BTreeSet::from([manager]),
+            ..Default::default()
+        };
+        // the collections stored before roles have no `roles` field
+        let mut data = ciborium::Value::serialized(&legacy).unwrap();
+        if let ciborium::Value::Map(fields) = &mut data {
+            fields.retain(|(k, _)| k.as_text() != Some("roles"));
+        }
+        let mut buf = vec![];
+        into_writer(&data, &mut buf).unwrap();
+        let decoded = Collection::from_bytes(Cow::Owned(buf));
+        assert_eq!(decoded.minters, legacy.minters);
+        assert_eq!(decoded.managers, legacy.managers);
+        assert!(decoded.roles.is_empty());
+
+        collection::with_mut(|c| *c = decoded);
+        collection::init_roles();
+        collection::with(|c| {
+            assert!(c.minters.is_empty());
+            assert!(c.managers.is_empty());
+            assert_eq!(c.roles.len(), 2);
+            assert_eq!(c.roles[&minter], BTreeSet::from([Role::Minter]));
+            assert_eq!(
+                c.roles[&manager],
+                BTreeSet::from([
+                    Role::CollectionEditor,
+                    Role::TokenCreator,
+                    Role::Minter,
+                    Role::Burner,
+                    Role::AssetUploader,
+                ])
+            );
+            assert!(!c.has_role(&manager, Role::Pauser));
+        });
+
+        // it is a no-op after the migration
+        collection::init_roles();
+        collection::with(|c| assert_eq!(c.roles.len(), 2));
+    }
+
+    #[test]
+    fn test_token_minters() {
+        let minter = Principal::self_authenticating(b"minter public key");
+        let grant = MintGrant {
+            quota: None,
+            expires_at: 0,
+        };
+        assert!(grant.is_active(0));
+        assert!(grant.is_active(u64::MAX));
+        let grant = MintGrant {
+            quota: Some(3),
+            expires_at: 100,
+        };
+        assert!(grant.is_active(99));
+        assert!(!grant.is_active(100));
+        assert!(!grant.is_active(101));
+
+        assert!(token_minters::set(1, minter, grant).is_none());
+        assert_eq!(token_minters::get(1, minter, 99).unwrap().quota, Some(3));
+        assert!(token_minters::get(2, minter, 99).is_none());
+        // expired grants are not returned, but are still listed
+        assert!(token_minters::get(1, minter, 100).is_none());
+        assert_eq!(token_minters::list(1).len(), 1);
+
+        token_minters::consume(1, minter, 2);
+        assert_eq!(token_minters::get(1, minter, 99).unwrap().quota, Some(1));
+        token_minters::consume(1, minter, 2);
+        assert_eq!(token_minters::get(1, minter, 99).unwrap().quota, Some(0));
+
+        // an unlimited grant is not consumed
+        token_minters::set(
+            2,
+            minter,
+            MintGrant {
+                quota: None,
+                expires_at: 0,
+            },
+        );
+        token_minters::consume(2, minter, 10);
+        assert_eq!(token_minters::get(2, minter, 99).unwrap().quota, None);
+
+        assert!(token_minters::remove(1, minter).is_some());
+        assert!(token_minters::get(1, minter, 0).is_none());
+        assert!(token_minters::list(1).is_empty());
+    }
+
+    #[test]
+    fn test_approvals() {
+        let alice = AccountKey::from(Principal::self_authenticating(b"alice public key"));
+        let bob = AccountKey::from(Principal::self_authenticating(b"bob public key"));
+        let carol = AccountKey::from(Principal::self_authenticating(b"carol public key"));
+        let mut items = Approvals::default();
+        // 0 means the approval never expires
+        items.insert(alice, 10, 0);
+        items.insert(bob, 10, 100);
+        assert!(items.is_approved(&alice, u64::MAX));
+        assert!(items.is_approved(&bob, 99));
+        assert!(!items.is_approved(&bob, 100));
+        assert!(!items.is_approved(&carol, 0));
+
+        let info = Approvals::to_info(&carol, (&alice, &items.get(&alice).unwrap()));
+        assert_eq!(info.expires_at, None);
+        assert_eq!(info.created_at_time, Some(10 * SECOND));
+        let info = Approvals::to_info(&carol, (&bob, &items.get(&bob).unwrap()));
+        assert_eq!(info.expires_at, Some(100 * SECOND));
+
+        assert_eq!(items.prune(99), 0);
+        assert_eq!(items.prune(100), 1);
+        assert_eq!(items.total(), 1);
+        assert!(items.get(&bob).is_none());
+        assert!(items.is_approved(&alice, 100));
+
+        // the collection approvals
+        approvals::with_mut(|r| {
+            r.insert(carol, items.clone());
+            let mut expiring = Approvals::default();
+            expiring.insert(alice, 10, 50);
+            r.insert(bob, expiring);
+        });
+        assert!(approvals::is_approved(&carol, &alice, u64::MAX));
+        assert!(approvals::is_approved(&bob, &alice, 49));
+        assert!(!approvals::is_approved(&bob, &alice, 50));
+        let args = [(SftId(1, 1), carol), (SftId(1, 2), bob)];
+        let unapproved = approvals::find_unapproved(&alice, &args, 50);
+        assert_eq!(unapproved.len(), 1);
+        assert_eq!(unapproved[0].1, bob);
+    }
+
+    #[test]
+    fn test_approvals_limit() {
+        let holder = AccountKey::from(Principal::self_authenticating(b"holder public key"));
+        let spenders: Vec<AccountKey> = (0u8..3)
+            .map(|i| AccountKey::from(Principal::self_authenticating([i])))
+            .collect();
+        let mut tokens = HolderTokens::default();
+        tokens.0.entry(1).or_default().insert(1, None);
+        tokens.0.entry(1).or_default().insert(2, None);
+        assert!(tokens
+            .insert_approvals(2, 1, 3, spenders[0], 0, 0, 0)
+            .is_err());
+        assert!(tokens
+            .insert_approvals(2, 2, 1, spenders[0], 0, 0, 0)
+            .is_err());
+
+        tokens
+            .insert_approvals(2, 1, 1, spenders[0], 0, 0, 0)
+            .unwrap();
+        tokens
+            .insert_approvals(2, 1, 1, spenders[1], 0, 100, 0)
+            .unwrap();
+        // the limit is per token
+        assert!(tokens
+            .insert_approvals(2, 1, 1, spenders[2], 0, 0, 50)
+            .is_err());
+        tokens
+            .insert_approvals(2, 1, 2, spenders[2], 0, 0, 50)
+            .unwrap();
+        // an existing approval can be renewed at the limit
+        tokens
+            .insert_approvals(2, 1, 1, spenders[1], 0, 200, 50)
+            .unwrap();
+        assert!(tokens
+            .insert_approvals(2, 1, 1, spenders[2], 0, 0, 150)
+            .is_err());
+        // the approval of spender 1 has expired at 200
+        tokens
+            .insert_approvals(2, 1, 1, spenders[2], 0, 0, 200)
+            .unwrap();
+        assert_eq!(tokens.get_approvals(1, 1).unwrap().total(), 2);
+        assert!(tokens
+            .get_approvals(1, 1)
+            .unwrap()
+            .get(&spenders[1])
+            .is_none());
+
+        // the expired approvals are swept by the timer
+        tokens
+            .insert_approvals(2, 1, 2, spenders[1], 0, 300, 200)
+            .unwrap();
+        holder_tokens::with_mut(|r| r.insert(holder, tokens));
+        let mut expiring = Approvals::default();
+        expiring.insert(spenders[0], 0, 300);
+        approvals::with_mut(|r| r.insert(holder, expiring));
+
+        assert!(holder_tokens::sweep_expired(None, 300, || false).is_some());
+        assert_eq!(holder_tokens::sweep_expired(None, 300, || true), None);
+        assert_eq!(approvals::sweep_expired(None, 300, || true), None);
+        let tokens = holder_tokens::with(|r| r.get(&holder)).unwrap();
+        assert_eq!(tokens.get_approvals(1, 2).unwrap().total(), 1);
+        assert_eq!(tokens.balance_of(), 2);
+        assert!(approvals::with(|r| r.get(&holder)).is_none());
+    }
+
+    #[test]
+    fn test_audit_logs() {
+        let actor = Principal::self_authenticating(b"editor public key");
+        for i in 0..3u64 {
+            let index = audit_logs::append(
+                actor,
+                "sft_update_collection",
+                Metadata::new(),
+                Metadata::new(),
+                i,
+            );
+            assert_eq!(index, i);
+        }
+        let indexes = |prev: Option<Nat>, take: u64| -> Vec<u64> {
+            audit_logs::get(prev.as_ref(), take)
+                .into_iter()
+                .map(|(i, _)| i)
+                .collect()
+        };
+        assert_eq!(indexes(None, 10), vec![0, 1, 2]);
+        assert_eq!(indexes(None, 2), vec![0, 1]);
+        assert_eq!(indexes(Some(Nat::from(0u64)), u64::MAX), vec![1, 2]);
+        assert!(indexes(Some(Nat::from(2u64)), 10).is_empty());
+
+        // the cursor saturates instead of wrapping to the first log
+        assert!(indexes(Some(Nat::from(u64::MAX)), u64::MAX).is_empty());
+        assert!(indexes(Some(Nat::from(u64::MAX) + Nat::from(1u64)), 10).is_empty());
+        assert!(indexes(Some(Nat::from(u128::MAX)), 10).is_empty());
+    }
+
+    #[test]
+    fn test_holders() {
+        let alice = AccountKey::from(Principal::self_authenticating(b"alice public key"));
+        let bob = AccountKey::from(Principal::self_authenticating(b"bob public key"));
+        let mut holders = Holders::default();
+        assert_eq!(holders.unique(), 0);
+        assert_eq!(holders.iter_from(0).count(), 0);
+
+        for holder in [alice, bob, alice, bob] {
+            holders.append(holder);
+        }
+        assert_eq!(holders.unique(), 2);
+        assert_eq!(holders.burn(Some(&bob), 2).unwrap(), bob);
+        assert_eq!(holders.burn(None, 4).unwrap(), bob);
+        assert!(holders.burn(None, 4).is_err());
+        assert_eq!(holders.total(), 4);
+        assert_eq!(holders.unique(), 1);
+
+        // burned tokens are skipped
+        let res: Vec<(u32, AccountKey)> = holders.iter_from(0).map(|(i, h)| (i, *h)).collect();
+        assert_eq!(res, vec![(1, alice), (3, alice)]);
+        let res: Vec<u32> = holders.iter_from(1).map(|(i, _)| i).collect();
+        assert_eq!(res, vec![3]);
+        assert_eq!(holders.iter_from(3).count(), 0);
+        assert_eq!(holders.iter_from(10).count(), 0);
+    }
+
+    // The token fixture shared by the tests of the API modules.
+    pub(crate) fn new_token(id: u32, supply_cap: Option<u32>) -> Token {
+        Token {
+            id,
+            name: format!("Token {}", id),
+            description: None,
+            asset_name: "token.svg".to_string(),
+            asset_content_type: "image/svg+xml".to_string(),
+            asset_hash: [0u8; 32],
+            metadata: Metadata::new(),
+            author: Principal::anonymous(),
+            supply_cap,
+            total_supply: 0,
+            minted: 0,
+            created_at: 0,
+            updated_at: 0,
+            assets: BTreeMap::new(),
+        }
     }
 
-    pub fn with_mut<R>(f: impl FnOnce(&mut StableBTreeMap<[u8; 32], Vec<u8>, Memory>) -> R) -> R {
-        ASSETS.with(|r| f(&mut r.borrow_mut()))
+    #[test]
+    fn test_supply_cap() {
+        let mut token = new_token(1, Some(3));
+        assert!(token.can_mint(3));
+        assert!(!token.can_mint(4));
+        token.minted = 2;
+        token.total_supply = 1;
+        // the burned units are counted
+        assert!(token.can_mint(1));
+        assert!(!token.can_mint(2));
+        token.supply_cap = None;
+        assert!(token.can_mint(u32::MAX));
+
+        let mut c = Collection {
+            supply_cap: Some(10),
+            token_types: 20, // the token types are not capped
+            total_minted: 8,
+            total_burned: 5,
+            ..Default::default()
+        };
+        assert!(c.can_mint(2));
+        assert!(!c.can_mint(3));
+        c.total_minted = 10;
+        assert!(c.can_mint(0));
+        assert!(!c.can_mint(1));
+        c.supply_cap = None;
+        assert!(c.can_mint(u64::MAX));
+    }
+
+    #[test]
+    fn test_collection_token_types() {
+        // the collection stored before the token types were renamed
+        #[derive(Serialize)]
+        struct LegacyCollection {
+            symbol: String,
+            name: String,
+            total_supply: u64,
+            created_at: u64,
+            updated_at: u64,
+            archived_blocks: u64,
+            settings: Settings,
+        }
+
+        let mut buf = vec![];
+        into_writer(
+            &LegacyCollection {
+                symbol: "SFT".to_string(),
+                name: "Collection".to_string(),
+                total_supply: 3,
+                created_at: 0,
+                updated_at: 0,
+                archived_blocks: 0,
+                settings: Settings::default(),
+            },
+            &mut buf,
+        )
+        .unwrap();
+        let c = Collection::from_bytes(Cow::Owned(buf));
+        assert_eq!(c.token_types, 3);
+        assert_eq!(c.circulating_supply(), 0);
+        let c = Collection::from_bytes(c.to_bytes());
+        assert_eq!(c.token_types, 3);
+    }
+
+    #[test]
+    fn test_init_counters() {
+        let alice = AccountKey::from(Principal::self_authenticating(b"alice public key"));
+        for (id, minted, burned) in [(1u32, 3u32, 1u32), (2, 0, 0), (3, 2, 2)] {
+            let mut token = new_token(id, None);
+            token.total_supply = minted - burned;
+            tokens::with_mut(|r| r.push(&token)).unwrap();
+
+            if minted > 0 {
+                let mut holders = Holders::default();
+                for _ in 0..minted {
+                    holders.append(alice);
+                }
+                for sid in 1..=burned {
+                    holders.burn(None, sid).unwrap();
+                }
+                holders::with_mut(|r| r.insert(id, holders));
+            }
+        }
+
+        indexes::start();
+        assert!(indexes::rebuild(|| true));
+        collection::with(|c| {
+            assert_eq!(c.total_minted, 5);
+            assert_eq!(c.total_burned, 3);
+            assert_eq!(c.circulating_supply(), 2);
+        });
+        tokens::with(|r| {
+            let minted: Vec<u32> = r.iter().map(|t| t.minted).collect();
+            assert_eq!(minted, vec![3, 0, 2]);
+        });
+
+        // the counters tracked after the upgrade are kept
+        collection::with_mut(|c| c.total_minted = 6);
+        indexes::start();
+        collection::with(|c| {
+            assert!(c.index_rebuild.is_none());
+            assert_eq!(c.total_minted, 6);
+        });
+
+        // the counters of a collection that has minted nothing are not counted again
+        collection::with_mut(|c| {
+            c.total_minted = 0;
+            c.total_burned = 0;
+        });
+        indexes::start();
+        collection::with(|c| assert!(c.index_rebuild.is_none()));
+    }
+
+    #[test]
+    fn test_asset_tokens() {
+        let mut token = new_token(1, None);
+        token.assets.insert(
+            "thumbnail".to_string(),
+            TokenAsset {
+                name: "thumbnail.png".to_string(),
+                content_type: "image/png".to_string(),
+                hash: [0u8; 32],
+            },
+        );
+        assert_eq!(token.asset_hashes(), [[0u8; 32]].into());
+        tokens::with_mut(|r| r.push(&token)).unwrap();
+        tokens::with_mut(|r| r.push(&new_token(2, None))).unwrap();
+        indexes::start();
+        assert!(indexes::rebuild(|| true));
+        assert_eq!(asset_tokens::list(&[0u8; 32]), vec![1, 2]);
+
+        let prev = token.asset_hashes();
+        token.asset_hash = [1u8; 32];
+        asset_tokens::update(1, &prev, &token.asset_hashes());
+        assert_eq!(asset_tokens::list(&[0u8; 32]), vec![1, 2]);
+        assert_eq!(asset_tokens::list(&[1u8; 32]), vec![1]);
+
+        let prev = token.asset_hashes();
+        token.assets.clear();
+        asset_tokens::update(1, &prev, &token.asset_hashes());
+        assert_eq!(asset_tokens::list(&[0u8; 32]), vec![2]);
+        assert_eq!(asset_tokens::list(&[1u8; 32]), vec![1]);
+        assert!(asset_tokens::list(&[2u8; 32]).is_empty());
+    }
+
+    #[test]
+    fn test_rebuild_indexes() {
+        let author = Principal::self_authenticating(b"author public key");
+        let rarity = Value::Text("legendary".to_string());
+        for id in 1..=3u32 {
+            let mut token = new_token(id, None);
+            token.author = author;
+            token.metadata.insert("rarity".to_string(), rarity.clone());
+            tokens::with_mut(|r| r.push(&token)).unwrap();
+        }
+        indexes::start();
+        // the token created during the rebuild is indexed when it is created
+        tokens::with_mut(|r| r.push(&new_token(4, None))).unwrap();
+        assets::add_ref([0u8; 32]);
+
+        let steps = std::cell::Cell::new(0);
+        let has_budget = || {
+            steps.set(steps.get() + 1);
+            steps.get() % 3 != 0
+        };
+        assert!(!indexes::rebuild(has_budget));
+        assert!(indexes::ensure_built().is_err());
+        collection::with(|c| assert_eq!(c.index_rebuild.as_ref().unwrap().next, 2));
+        assert!(indexes::rebuild(has_budget));
+        assert!(indexes::ensure_built().is_ok());
+        collection::with(|c| {
+            assert!(c.index_rebuild.is_none());
+            assert_eq!(c.indexes, Index::ALL.into());
+        });
+
+        assert_eq!(assets::refs(&[0u8; 32]), 4);
+        assert_eq!(asset_tokens::list(&[0u8; 32]), vec![1, 2, 3]);
+        assert_eq!(author_tokens::list(author, 0, 10), vec![1, 2, 3]);
+        let mut tids = Vec::new();
+        attributes::scan(attributes::hash("rarity", &rarity).unwrap(), 0, |tid| {
+            tids.push(tid);
+            true
+        });
+        assert_eq!(tids, vec![1, 2, 3]);
+
+        // the built indexes are not rebuilt
+        indexes::start();
+        collection::with(|c| assert!(c.index_rebuild.is_none()));
+    }
+
+    #[test]
+    fn test_attributes_hash() {
+        let text = Value::Text("legendary".to_string());
+        let nat = Value::Nat(7u64.into());
+        let hash = attributes::hash("rarity", &text).unwrap();
+        assert_eq!(attributes::hash("rarity", &text), Some(hash));
+        assert_ne!(attributes::hash("Rarity", &text), Some(hash));
+        assert_ne!(
+            attributes::hash("rarity", &Value::Text("Legendary".to_string())),
+            Some(hash)
+        );
+        assert_ne!(
+            attributes::hash("level", &nat),
+            attributes::hash("rarity", &nat)
+        );
+        // the value type is a part of the key
+        assert_ne!(
+            attributes::hash("level", &Value::Text("\u{7}".to_string())),
+            attributes::hash("level", &nat)
+        );
+        // the key and the value are separated
+        assert_ne!(
+            attributes::hash("ab", &Value::Text("c".to_string())),
+            attributes::hash("a", &Value::Text("bc".to_string()))
+        );
+        assert!(attributes::hash("image", &Value::Blob(ByteBuf::from(vec![1u8]))).is_none());
+        assert!(attributes::hash("supply", &Value::Int(1i64.into())).is_none());
+    }
+
+    #[test]
+    fn test_assets_chunks() {
+        let content: Vec<u8> = (0..assets::ASSET_CHUNK_SIZE * 2 + 1)
+            .map(|i| i as u8)
+            .collect();
+        let hash = sha3_256(&content);
+        assert!(assets::insert([0u8; 32], "video/mp4", &content).is_err());
+        assert!(!assets::exists(&[0u8; 32]));
+        assert!(assets::chunk(&[0u8; 32], 0).is_none());
+
+        assets::insert(hash, "video/mp4", &content).unwrap();
+        let meta = assets::meta(&hash).unwrap();
+        assert_eq!(meta.content_type, "video/mp4");
+        assert_eq!(meta.size, content.len() as u64);
+        assert_eq!(meta.sha256, <[u8; 32]>::from(Sha256::digest(&content)));
+        assert_eq!(meta.chunks.len(), 3);
+        assert_eq!(
+            assets::chunk(&hash, 2).unwrap(),
+            vec![content[content.len() - 1]]
+        );
+        let chunks: Vec<u8> = (0..3)
+            .flat_map(|i| assets::chunk(&hash, i).unwrap())
+            .collect();
+        assert_eq!(chunks, content);
+        assert!(assets::chunk(&hash, 3).is_none());
+        assert_eq!(assets::refs(&hash), 0);
+
+        // the assets stored as whole contents
+        let legacy = b"legacy asset".to_vec();
+        let legacy_hash = sha3_256(&legacy);
+        ASSETS.with(|r| r.borrow_mut().insert(legacy_hash, legacy.clone()));
+        assert!(assets::has_legacy());
+        assert_eq!(assets::size(&legacy_hash), Some(legacy.len() as u64));
+        assert_eq!(assets::chunk(&legacy_hash, 0).unwrap(), legacy);
+        let mut migrated = Vec::new();
+        assert!(assets::migrate(
+            |hash| (hash == &legacy_hash).then(|| "image/png".to_string()),
+            |hash| migrated.push(*hash),
+            || true
+        ));
+        assert_eq!(migrated, vec![legacy_hash]);
+        assert!(!assets::has_legacy());
+        let meta = assets::meta(&legacy_hash).unwrap();
+        assert_eq!(meta.content_type, "image/png");
+        assert_eq!(meta.chunks.len(), 1);
+        assert_eq!(assets::chunk(&legacy_hash, 0).unwrap(), legacy);
+
+        assets::remove(&hash);
+        assert!(!assets::exists(&hash));
+        assert!(assets::chunk(&hash, 0).is_none());
+    }
+
+    #[test]
+    fn test_assets_encodings() {
+        let gzip = |data: &[u8]| {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+            std::io::Write::write_all(&mut encoder, data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let content = b"<svg></svg>".to_vec();
+        let hash = sha3_256(&content);
+        let encoded = gzip(&content);
+        let encoded_hash = sha3_256(&encoded);
+        assets::insert(hash, "image/svg+xml", &content).unwrap();
+        assert!(assets::set_encoding(hash, 1, Some(encoded_hash)).is_err());
+        assert!(assets::set_encoding(hash, 1, Some(hash)).is_err());
+
+        // the variants that do not decode to the content are rejected
+        for (code, data) in [
+            (1, b"not gzip".to_vec()),
+            (1, gzip(b"<svg>other</svg>")),
+            (1, gzip(b"<svg></svg><svg></svg>")),
+            (2, encoded.clone()),
+        ] {
+            let other = sha3_256(&data);
+            assets::insert(other, "application/octet-stream", &data).unwrap();
+            assert!(assets::set_encoding(hash, code, Some(other)).is_err());
+            assets::remove(&other);
+        }
+
+        assets::insert(encoded_hash, "image/svg+xml", &encoded).unwrap();
+        assets::set_encoding(hash, 1, Some(encoded_hash)).unwrap();
+        assert_eq!(
+            assets::meta(&hash).unwrap().encodings.get(&1),
+            Some(&encoded_hash)
+        );
+        assert_eq!(assets::refs(&encoded_hash), 1);
+        assets::set_encoding(hash, 1, Some(encoded_hash)).unwrap();
+        assert_eq!(assets::refs(&encoded_hash), 1);
+
+        // the encoded variant is removed with the asset
+        assets::remove(&hash);
+        assert!(!assets::exists(&encoded_hash));
+
+        assets::insert(hash, "image/svg+xml", &content).unwrap();
+        assets::insert(encoded_hash, "image/svg+xml", &encoded).unwrap();
+        assets::set_encoding(hash, 1, Some(encoded_hash)).unwrap();
+        assets::set_encoding(hash, 1, None).unwrap();
+        assert!(assets::meta(&hash).unwrap().encodings.is_empty());
+        assert!(!assets::exists(&encoded_hash));
+
+        // the large assets have no encoded variants
+        assets::insert(encoded_hash, "image/svg+xml", &encoded).unwrap();
+        let large = AssetMeta {
+            size: assets::MAX_ENCODED_ASSET_SIZE + 1,
+            ..assets::meta(&hash).unwrap()
+        };
+        ASSET_META.with(|r| r.borrow_mut().insert(hash, large));
+        assert!(assets::set_encoding(hash, 1, Some(encoded_hash)).is_err());
+    }
+
+    #[test]
+    fn test_uploads() {
+        let owner = Principal::self_authenticating(b"uploader public key");
+        let content = vec![7u8; assets::ASSET_CHUNK_SIZE + 10];
+        let hash = sha3_256(&content);
+        let id = uploads::create(
+            owner,
+            hash,
+            content.len() as u64,
+            "image/png".to_string(),
+            0,
+        );
+        let chunks: Vec<&[u8]> = content.chunks(assets::ASSET_CHUNK_SIZE).collect();
+        // the chunks are uploaded in order, with the size of the asset chunks
+        assert!(uploads::put_chunk(&owner, id, 1, chunks[1].to_vec()).is_err());
+        assert!(uploads::put_chunk(&owner, id, 0, chunks[1].to_vec()).is_err());
+        for (index, chunk) in chunks.iter().enumerate() {
+            uploads::put_chunk(&owner, id, index as u32, chunk.to_vec()).unwrap();
+        }
+        assert!(uploads::put_chunk(&owner, id, 2, vec![7u8]).is_err());
+        assert!(uploads::commit(&Principal::anonymous(), id, 1).is_err());
+        assert_eq!(uploads::commit(&owner, id, 100).unwrap(), hash);
+        assert!(uploads::commit(&owner, id, 100).is_err());
+        assert!(uploads::put_chunk(&owner, id, 0, vec![7u8]).is_err());
+
+        // the chunks of the upload are the asset chunks
+        let meta = assets::meta(&hash).unwrap();
+        assert_eq!(meta.upload_id, Some(id));
+        assert_eq!(meta.size, content.len() as u64);
+        assert_eq!(meta.sha256, <[u8; 32]>::from(Sha256::digest(&content)));
+        assert_eq!(assets::chunk(&hash, 0).unwrap(), chunks[0]);
+        assert_eq!(assets::chunk(&hash, 1).unwrap(), chunks[1]);
+        assert_eq!(assets::chunk(&hash, 2), None);
+
+        // the committed asset is referenced by the upload until it expires
+        assert_eq!(assets::refs(&hash), 1);
+        assert!(assets::orphans(None, 10).is_empty());
+        assets::add_ref(hash);
+        assert!(!assets::release(&hash));
+        assert!(assets::exists(&hash));
+
+        let expiry = uploads::UPLOAD_EXPIRY_SEC;
+        uploads::create(owner, [0u8; 32], 1, "image/png".to_string(), 100 + expiry);
+        assert!(assets::exists(&hash));
+        uploads::create(owner, [0u8; 32], 1, "image/png".to_string(), 101 + expiry);
+        assert_eq!(assets::refs(&hash), 0);
+        assert!(!assets::exists(&hash));
+        assert_eq!(assets::chunk(&hash, 0), None);
+        assert!(UPLOAD_CHUNKS.with(|r| r.borrow().is_empty()));
+
+        // the upload mismatching the hash is removed
+        let id = uploads::create(owner, [1u8; 32], 1, "image/png".to_string(), 200 + expiry);
+        uploads::put_chunk(&owner, id, 0, vec![7u8]).unwrap();
+        assert!(uploads::commit(&owner, id, 200 + expiry).is_err());
+        assert!(uploads::put_chunk(&owner, id, 0, vec![7u8]).is_err());
+        assert!(UPLOAD_CHUNKS.with(|r| r.borrow().is_empty()));
+
+        // the hashers are lost on upgrade
+        let id = uploads::create(
+            owner,
+            hash,
+            content.len() as u64,
+            "image/png".to_string(),
+            300 + expiry,
+        );
+        uploads::put_chunk(&owner, id, 0, chunks[0].to_vec()).unwrap();
+        UPLOAD_HASHERS.with(|r| r.borrow_mut().clear());
+        assert!(uploads::put_chunk(&owner, id, 1, chunks[1].to_vec()).is_err());
+
+        assets::insert(hash, "image/png", &content).unwrap();
+        assert!(!assets::set_content_type(&hash, "image/png"));
+        assert!(assets::set_content_type(&hash, "image/webp"));
+        assert_eq!(assets::meta(&hash).unwrap().content_type, "image/webp");
+    }
+
+    #[test]
+    fn test_http_tree_wildcard() {
+        let expr = [1u8; 32];
+        http_tree::insert(&[], true, expr, &[[2u8; 32]]);
+        http_tree::insert(&["token"], true, expr, &[[3u8; 32]]);
+        http_tree::insert_response(&["token"], true, expr, [4u8; 32]);
+        http_tree::insert(&["token", "1"], false, expr, &[[5u8; 32]]);
+        assert!(http_tree::contains(&[], true));
+        assert!(http_tree::contains(&["token"], true));
+        assert!(!http_tree::contains(&["asset"], true));
+        assert!(http_tree::contains(&["token", "1"], false));
+
+        // the witnesses are pruned from the certified data
+        let root_hash = collection::with(|c| c.root_hash());
+        assert_eq!(http_tree::witness(&["token", "1"]).digest(), root_hash);
+        for (segments, prefix, hash) in [
+            (vec!["token", "9"], 1, [4u8; 32]),
+            (vec!["token", "9", "thumbnail"], 1, [3u8; 32]),
+            (vec!["asset", "00"], 0, [2u8; 32]),
+            (vec![""], 0, [2u8; 32]),
+        ] {
+            let tree = http_tree::witness_wildcard(&segments, prefix, expr, hash);
+            assert_eq!(tree.digest(), root_hash);
+        }
+
+        http_tree::remove(&["token", "1"]);
+        assert!(!http_tree::contains(&["token", "1"], false));
+        assert!(http_tree::contains(&["token"], true));
     }
 }
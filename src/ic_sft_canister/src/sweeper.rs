@@ -0,0 +1,76 @@
+use crate::{api_sft_http, store, SECOND};
+use std::{cell::RefCell, time::Duration};
+
+// The maximum number of instructions used by one sweeping tick.
+const MAX_SWEEP_INSTRUCTIONS: u64 = 2_000_000_000;
+
+thread_local! {
+    // the keys to resume sweeping from, None means starting from the first key
+    static APPROVALS_CURSOR: RefCell<Option<store::AccountKey>> = const { RefCell::new(None) };
+    static HOLDER_TOKENS_CURSOR: RefCell<Option<store::AccountKey>> = const { RefCell::new(None) };
+    // the index of the next token to certify
+    static CERTIFY_CURSOR: RefCell<u32> = const { RefCell::new(0) };
+}
+
+// Removes the expired collection approvals and token approvals incrementally.
+// It is called by the timer periodically and resumes from where the last tick stopped.
+pub fn sweep_expired_approvals() {
+    let now_sec = ic_cdk::api::time() / SECOND;
+    let limit = ic_cdk::api::instruction_counter().saturating_add(MAX_SWEEP_INSTRUCTIONS);
+    let has_budget = || ic_cdk::api::instruction_counter() < limit;
+
+    let cursor = APPROVALS_CURSOR.with(|r| r.borrow_mut().take());
+    let cursor = store::approvals::sweep_expired(cursor, now_sec, has_budget);
+    let done = cursor.is_none();
+    APPROVALS_CURSOR.with(|r| *r.borrow_mut() = cursor);
+    if !done || !has_budget() {
+        return;
+    }
+
+    let cursor = HOLDER_TOKENS_CURSOR.with(|r| r.borrow_mut().take());
+    let cursor = store::holder_tokens::sweep_expired(cursor, now_sec, has_budget);
+    HOLDER_TOKENS_CURSOR.with(|r| *r.borrow_mut() = cursor);
+}
+
+// Certifies the tokens after upgrade as the certified responses are kept in the heap, it schedules itself
+// again until all the tokens are certified. The responses of the tokens not certified yet have no certificate.
+pub fn certify_tokens() {
+    let limit = ic_cdk::api::instruction_counter().saturating_add(MAX_SWEEP_INSTRUCTIONS);
+    let next = CERTIFY_CURSOR.with(|r| *r.borrow());
+    let next = api_sft_http::certify_tokens(next, || ic_cdk::api::instruction_counter() < limit);
+    store::collection::with(|c| ic_cdk::api::set_certified_data(&c.root_hash()));
+    if let Some(next) = next {
+        CERTIFY_CURSOR.with(|r| *r.borrow_mut() = next);
+        ic_cdk_timers::set_timer(Duration::from_nanos(0), certify_tokens);
+    }
+}
+
+// Rebuilds the indexes missing after upgrade from the tokens, it schedules itself again until all the tokens
+// are indexed. Then the legacy assets are moved, as moving them looks up the indexes.
+pub fn rebuild_indexes() {
+    let limit = ic_cdk::api::instruction_counter().saturating_add(MAX_SWEEP_INSTRUCTIONS);
+    if store::indexes::rebuild(|| ic_cdk::api::instruction_counter() < limit) {
+        ic_cdk_timers::set_timer(Duration::from_nanos(0), migrate_assets);
+    } else {
+        ic_cdk_timers::set_timer(Duration::from_nanos(0), rebuild_indexes);
+    }
+}
+
+// Moves the assets stored as whole contents before chunking into chunks, it schedules itself
+// again until all the assets are moved. The tokens of each moved asset are certified in the same tick.
+pub fn migrate_assets() {
+    if !store::assets::has_legacy() || store::indexes::ensure_built().is_err() {
+        return;
+    }
+
+    let limit = ic_cdk::api::instruction_counter().saturating_add(MAX_SWEEP_INSTRUCTIONS);
+    let done = store::assets::migrate(
+        store::asset_tokens::content_type,
+        api_sft_http::recertify_asset,
+        || ic_cdk::api::instruction_counter() < limit,
+    );
+    store::collection::with(|c| ic_cdk::api::set_certified_data(&c.root_hash()));
+    if !done {
+        ic_cdk_timers::set_timer(Duration::from_nanos(0), migrate_assets);
+    }
+}
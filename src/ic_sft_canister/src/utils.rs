@@ -1,5 +1,7 @@
+use candid::{encode_args, CandidType, Principal};
 use ciborium::{from_reader, into_writer};
 use hmac::{Hmac, Mac};
+use ic_sft_types::{Metadata, Value};
 use serde::Serialize;
 use serde_bytes::ByteBuf;
 use sha3::{Digest, Sha3_256};
@@ -32,6 +34,82 @@ pub fn to_cbor_bytes(obj: &impl Serialize) -> Vec<u8> {
     buf
 }
 
+// tx_hash returns the hash of a transaction for deduplication, it covers the operation, the caller and the arguments.
+pub fn tx_hash(op: &str, caller: &Principal, args: &impl CandidType) -> [u8; 32] {
+    sha3_256(&encode_args((op, caller, args)).expect("failed to encode transaction"))
+}
+
+// metadata_diff returns the changed entries as they were in `before` and as they are in `after`.
+// A removed key is only in the former, an added key is only in the latter.
+pub fn metadata_diff(before: &Metadata, after: &Metadata) -> (Metadata, Metadata) {
+    (
+        metadata_changed(before, after),
+        metadata_changed(after, before),
+    )
+}
+
+// metadata_changed returns the entries of `src` that are not the same in `other`.
+fn metadata_changed(src: &Metadata, other: &Metadata) -> Metadata {
+    src.iter()
+        .filter(|(k, v)| other.get(k.as_str()) != Some(*v))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+// to_json returns the JSON encoding of the given value, blobs are encoded as hex strings.
+pub fn to_json(value: &Value) -> String {
+    let mut out = String::new();
+    write_json(value, &mut out);
+    out
+}
+
+fn write_json(value: &Value, out: &mut String) {
+    match value {
+        Value::Blob(v) => write_json_string(&hex::encode(v), out),
+        Value::Text(v) => write_json_string(v, out),
+        Value::Nat(v) => out.push_str(&v.0.to_string()),
+        Value::Int(v) => out.push_str(&v.0.to_string()),
+        Value::Array(arr) => {
+            out.push('[');
+            for (i, v) in arr.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(v, out);
+            }
+            out.push(']');
+        }
+        Value::Map(map) => {
+            out.push('{');
+            for (i, (k, v)) in map.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json_string(k, out);
+                out.push(':');
+                write_json(v, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
 // Challenge is a trait for generating and verifying challenges.
 pub trait Challenge {
     fn challenge(&self, key: &[u8], timestamp: u64) -> Vec<u8>;
@@ -69,6 +147,49 @@ where
 mod test {
     use super::*;
 
+    #[test]
+    fn test_metadata_diff() {
+        let before: Metadata = [
+            ("name", Value::Text("a".to_string())),
+            ("rarity", Value::Text("common".to_string())),
+            ("level", Value::Nat(1u64.into())),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        let (prev, next) = metadata_diff(&before, &before);
+        assert!(prev.is_empty());
+        assert!(next.is_empty());
+
+        let mut after = before.clone();
+        after.insert("name".to_string(), Value::Text("b".to_string()));
+        after.remove("rarity");
+        after.insert("color".to_string(), Value::Text("red".to_string()));
+        let (prev, next) = metadata_diff(&before, &after);
+        assert_eq!(
+            prev.keys().collect::<Vec<_>>(),
+            vec!["name", "rarity"],
+            "the removed key is in the former"
+        );
+        assert_eq!(prev["name"], Value::Text("a".to_string()));
+        assert_eq!(
+            next.keys().collect::<Vec<_>>(),
+            vec!["color", "name"],
+            "the added key is in the latter"
+        );
+        assert_eq!(next["name"], Value::Text("b".to_string()));
+
+        // a nested map is compared as a whole
+        let nested = |m: &Metadata| -> Metadata {
+            [("metadata".to_string(), Value::Map(m.clone()))]
+                .into_iter()
+                .collect()
+        };
+        let (prev, next) = metadata_diff(&nested(&before), &nested(&after));
+        assert_eq!(prev, nested(&before));
+        assert_eq!(next, nested(&after));
+    }
+
     #[test]
     fn test_challenge() {
         let key = b"secret key";
@@ -82,4 +203,28 @@ mod test {
         assert!(challenge.verify(&key[1..], expire_at, &c).is_err());
         assert!(challenge.verify(key, expire_at + 1, &c).is_err());
     }
+
+    #[test]
+    fn test_to_json() {
+        let value = Value::Map(
+            [
+                ("name".to_string(), Value::Text("a \"b\"\n".to_string())),
+                ("supply".to_string(), Value::Nat(1000u64.into())),
+                ("hash".to_string(), Value::Blob(ByteBuf::from(vec![1, 255]))),
+                (
+                    "tags".to_string(),
+                    Value::Array(vec![
+                        Value::Int((-1i64).into()),
+                        Value::Text("".to_string()),
+                    ]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(
+            to_json(&value),
+            r#"{"hash":"01ff","name":"a \"b\"\n","supply":1000,"tags":[-1,""]}"#
+        );
+    }
 }